@@ -0,0 +1,60 @@
+#!/bin/sh
+# Compares the load speed (rows per second) of the default mode and of the bulk
+# mode (--bulk) on a generated site of ROWS posts, ROWS comments and ROWS votes.
+# The foreign keys are declared so that their columns get indexed.
+# The progress display is disabled (--quiet), no terminal is needed.
+# Usage: cargo build --release && ./bench-load.sh [ROWS]
+set -e
+
+ROWS=${1:-200000}
+DLRS=${DLRS:-target/release/dlrs}
+DIR=$(mktemp -d)
+trap 'rm -rf "$DIR"' EXIT
+
+SITE=bench.stackexchange.com
+mkdir -p "$DIR/data/$SITE"
+# Never downloaded, the XML files are already extracted
+echo "$SITE.7z http://localhost/$SITE.7z" > "$DIR/site.list"
+
+awk -v rows="$ROWS" 'BEGIN {
+  print "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<posts>"
+  for (i = 1; i <= rows; i++) {
+    if (i % 2) {
+      printf "  <row Id=\"%d\" PostTypeId=\"1\" AcceptedAnswerId=\"%d\" CreationDate=\"2010-07-28T19:04:21.300\" Score=\"%d\" ViewCount=\"%d\" Body=\"&lt;p&gt;How do I do %d things with &lt;code&gt;grep&lt;/code&gt;?&lt;/p&gt;\" OwnerUserId=\"%d\" LastActivityDate=\"2010-07-28T19:04:21.300\" Title=\"Question %d\" Tags=\"&lt;grep&gt;&lt;search&gt;\" AnswerCount=\"1\" CommentCount=\"1\" ContentLicense=\"CC BY-SA 2.5\" />\n", i, i + 1, i % 50, i % 1000, i, i % 5000, i
+    } else {
+      printf "  <row Id=\"%d\" PostTypeId=\"2\" ParentId=\"%d\" CreationDate=\"2010-07-28T19:15:05.000\" Score=\"%d\" Body=\"&lt;p&gt;Use &lt;code&gt;grep -r&lt;/code&gt; %d times.&lt;/p&gt;\" OwnerUserId=\"%d\" LastActivityDate=\"2010-07-28T19:15:05.000\" CommentCount=\"0\" ContentLicense=\"CC BY-SA 2.5\" />\n", i, i - 1, i % 20, i, i % 5000
+    }
+  }
+  print "</posts>"
+}' > "$DIR/data/$SITE/Posts.xml"
+
+awk -v rows="$ROWS" 'BEGIN {
+  print "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<comments>"
+  for (i = 1; i <= rows; i++) {
+    printf "  <row Id=\"%d\" PostId=\"%d\" Score=\"%d\" Text=\"Comment %d, this works with sed too\" CreationDate=\"2010-07-28T19:36:59.773\" UserId=\"%d\" ContentLicense=\"CC BY-SA 2.5\" />\n", i, (i % rows) + 1, i % 10, i, i % 5000
+  }
+  print "</comments>"
+}' > "$DIR/data/$SITE/Comments.xml"
+
+awk -v rows="$ROWS" 'BEGIN {
+  print "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<votes>"
+  for (i = 1; i <= rows; i++) {
+    printf "  <row Id=\"%d\" PostId=\"%d\" VoteTypeId=\"2\" CreationDate=\"2010-07-28T00:00:00.000\" />\n", i, (i % rows) + 1
+  }
+  print "</votes>"
+}' > "$DIR/data/$SITE/Votes.xml"
+
+TOTAL_ROWS=$((3 * ROWS))
+for MODE in default bulk; do
+  rm -f "$DIR"/bench.db*
+  FLAGS=--foreign-keys
+  if [ "$MODE" = bulk ]; then
+    FLAGS="$FLAGS --bulk"
+  fi
+  START=$(date +%s%N)
+  "$DLRS" load -f "$DIR/data" -s "$DIR/site.list" -d "$DIR/bench.db" --quiet $FLAGS
+  END=$(date +%s%N)
+  MS=$(((END - START) / 1000000))
+  RESULTS="$RESULTS$MODE: $TOTAL_ROWS rows in $MS ms, $((TOTAL_ROWS * 1000 / MS)) rows/s\n"
+done
+printf "$RESULTS"
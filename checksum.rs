@@ -0,0 +1,97 @@
+/*
+ * Checksums used to verify the integrity of the downloaded archives.
+ * They either come from an optional third column of the site list
+ * (`md5:<hex>` or `sha1:<hex>`) or from the metadata archive.org publishes
+ * for every item (see item_metadata.rs).
+ */
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+  Md5,
+  Sha1,
+}
+
+impl Display for Algorithm {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Algorithm::Md5 => formatter.write_str("md5"),
+      Algorithm::Sha1 => formatter.write_str("sha1"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksum {
+  pub algorithm: Algorithm,
+  // Lowercase hexadecimal digest
+  pub digest: String,
+}
+
+impl Checksum {
+  pub fn new(algorithm: Algorithm, digest: &str) -> std::result::Result<Checksum, String> {
+    let expected_length = match algorithm {
+      Algorithm::Md5 => 32,
+      Algorithm::Sha1 => 40,
+    };
+    if digest.len() != expected_length || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+      return Err(format!("invalid {} digest {:?}", algorithm, digest));
+    }
+    Ok(Checksum { algorithm, digest: digest.to_ascii_lowercase() })
+  }
+}
+
+// Accepts "md5:<hex>", "sha1:<hex>" or a bare digest whose algorithm is
+// deduced from its length.
+impl FromStr for Checksum {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Checksum, String> {
+    match s.split_once(':') {
+      Some(("md5", digest)) => Checksum::new(Algorithm::Md5, digest),
+      Some(("sha1", digest)) => Checksum::new(Algorithm::Sha1, digest),
+      Some((algorithm, _)) => Err(format!("unsupported checksum algorithm {:?}", algorithm)),
+      None if s.len() == 32 => Checksum::new(Algorithm::Md5, s),
+      None => Checksum::new(Algorithm::Sha1, s),
+    }
+  }
+}
+
+impl Display for Checksum {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    write!(formatter, "{}:{}", self.algorithm, self.digest)
+  }
+}
+
+pub enum Hasher {
+  Md5(Md5),
+  Sha1(Sha1),
+}
+
+impl Hasher {
+  pub fn new(algorithm: Algorithm) -> Hasher {
+    match algorithm {
+      Algorithm::Md5 => Hasher::Md5(Md5::new()),
+      Algorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+    }
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    match self {
+      Hasher::Md5(hasher) => hasher.update(data),
+      Hasher::Sha1(hasher) => hasher.update(data),
+    }
+  }
+
+  // Returns the lowercase hexadecimal digest
+  pub fn finalize(self) -> String {
+    match self {
+      Hasher::Md5(hasher) => format!("{:x}", hasher.finalize()),
+      Hasher::Sha1(hasher) => format!("{:x}", hasher.finalize()),
+    }
+  }
+}
@@ -1,91 +1,68 @@
 use clap::Parser;
 use error_chain::error_chain;
-use quick_xml::events::Event;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::File;
+use dlrs::{ledger, loader, sql_utils, writer};
 use sqlite::Connection;
 
-mod se_struct;
-mod sql_utils;
-
 error_chain! {
   foreign_links {
     Io(std::io::Error);
-    Parser(quick_xml::Error);
-    Deserializer(quick_xml::DeError);
-    Utf8Error(std::str::Utf8Error);
     SqliteError(sqlite::Error);
-    SqlUtilsError(sql_utils::Error);
+  }
+  links {
+    Loader(loader::Error, loader::ErrorKind);
   }
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Config {
-  /// XML file
-  #[arg(value_name = "FILE")]
-  xml_file: PathBuf,
+  /// XML files of a site dump (Posts.xml, Votes.xml...), recognized by their name or their root element
+  #[arg(value_name = "FILE", required = true)]
+  xml_files: Vec<PathBuf>,
   /// sqlite3 database file
   #[arg(value_name = "FILE")]
   sql_file: PathBuf,
 }
 
-fn get_site_from_filepath(filepath: &PathBuf) -> Result<String> {
-  let mut filepath = filepath.clone();
+fn get_site_from_filepath(filepath: &Path) -> Result<String> {
+  let mut filepath = filepath.to_path_buf();
   filepath.pop();
   return Ok(filepath.file_stem().ok_or("error")?.to_string_lossy().to_string());
 }
 
+// Loads an XML file into the table named after its directory, as dlrs does.
+fn decode(writer: &writer::Writer, xml_file: &Path) -> Result<()> {
+  let table_name = get_site_from_filepath(xml_file)?;
+  let mut reader = quick_xml::Reader::from_reader(std::io::BufReader::new(File::open(xml_file)?));
+  let root = loader::read_root(&mut reader)?;
+  // The files may have been renamed, the root element is the fallback
+  let filename = xml_file.file_name().map(|filename| filename.to_string_lossy().to_string())
+    .filter(|filename| loader::SE_FILES.iter().any(|file| format!("{}.xml", file) == *filename))
+    .or_else(|| root.as_deref().and_then(loader::get_se_filename))
+    .ok_or_else(|| format!("{}: unknown file, expected one of {}.xml or their root element",
+      xml_file.display(), loader::SE_FILES.join(".xml, ")))?;
+  let ledger_entry = ledger::Entry {
+    site: xml_file.parent().and_then(|dir| dir.file_name()).unwrap_or_default().to_string_lossy().to_string(),
+    stage: ledger::load_stage(&filename),
+    archives: ledger::load_archives(&xml_file.to_string_lossy(), &sql_utils::Options::default().schema_key()),
+  };
+  println!("{} -> {} table of {}", xml_file.display(), filename, table_name);
+  let count = loader::inject_se_file(writer, &filename, &mut reader, &table_name, &ledger_entry,
+    &sql_utils::Options::default(), false)?;
+  println!("{} entries.", count.unwrap_or_default());
+  Ok(())
+}
+
 fn main() -> Result<()> {
   let config = Config::parse();
 
   let connection = Connection::open(&config.sql_file)?;
-
-  let f = File::open(&config.xml_file)?;
-  let table_name = get_site_from_filepath(&config.xml_file)?;
-  println!("table_name {}",table_name);
-  let bufreader = std::io::BufReader::new(f);
-  let mut reader = quick_xml::Reader::from_reader(bufreader);
-
-  let mut count = 0;
-  let mut insert_statement = connection.prepare("")?;
-  connection.execute("BEGIN TRANSACTION;")?;
-  loop {
-    let mut buf = Vec::new();
-    match reader.read_event_into(&mut buf) {
-      Err(e) => panic!(
-        "Error at position {}: {:?}",
-        reader.buffer_position(),
-        e
-      ),
-      Ok(Event::Eof) => break,
-      Ok(Event::Empty(e)) => {
-        let s = format!("<{}/>", std::str::from_utf8(&e)?);
-        println!("s {}", s);
-        let tag: se_struct::Badge = quick_xml::de::from_str(&s)?;
-        if count == 0 {
-          let (create_stmt, insert_stmt) = sql_utils::to_init_table(&tag, &table_name)?;
-          println!("{}", create_stmt);
-          println!("{}", insert_stmt);
-          connection.execute(create_stmt)?;
-          insert_statement = connection.prepare(insert_stmt)?;
-        }
-        insert_statement.reset()?;
-        let bindings = sql_utils::bind_stmt(&tag)?;
-        for (index, value) in bindings.iter().enumerate() {
-          insert_statement.bind((index + 1, value.as_str()))?;
-        }
-        insert_statement.next()?;
-        count += 1;
-        if count % 1_000_000 == 0 {
-          println!("{}", count);
-        }
-      },
-      _ => (),
-    }
-  }
-
-  connection.execute("END TRANSACTION;")?;
-  println!("{} entries.", count);
-  Ok(())
+  ledger::init(&connection)?;
+  let (writer, writer_thread) = writer::Writer::spawn(connection, sql_utils::Options::default(), false);
+  let result = config.xml_files.iter().try_for_each(|xml_file| decode(&writer, xml_file));
+  drop(writer);
+  writer_thread.join().map_err(|_| "the database writer panicked")??;
+  result
 }
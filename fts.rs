@@ -0,0 +1,144 @@
+/*
+ * Full-text indexes (SQLite FTS5) over the posts and comments of a site,
+ * rebuilt after each load.
+ * The indexes of a site with tables of its own are contentless, they only hold
+ * the tokens, and their rowid is the id of the post or comment they index.
+ * The sites sharing their tables (--unified) share their indexes too. These
+ * store their content, the rows of a site could not be removed otherwise, and
+ * the site_id and id of the post or comment in unindexed columns.
+ */
+
+use crate::sites::{table_exists, Site, Tables};
+use sqlite::{Connection, State, Statement};
+
+const TOKENIZE: &str = "tokenize='porter unicode61'";
+
+// Name of the index of the structures named `name` (e.g. "Post")
+fn get_index(site: &Site, name: &str) -> String {
+  match &site.tables {
+    Tables::Prefixed(_) => format!("{}Fts", site.table(name)),
+    Tables::Unified(_) => format!("{}_fts", site.table(name)),
+  }
+}
+
+// Removes the tags of an HTML fragment and decodes its entities.
+pub fn strip_html(html: &str) -> String {
+  let mut text = String::with_capacity(html.len());
+  let mut in_tag = false;
+  for c in html.chars() {
+    match c {
+      '<' => in_tag = true,
+      // Tags usually separate words (<p>, <li>, <br/>...)
+      '>' if in_tag => {
+        in_tag = false;
+        text.push(' ');
+      },
+      _ if !in_tag => text.push(c),
+      _ => (),
+    }
+  }
+  html_escape::decode_html_entities(&text).into_owned()
+}
+
+// "<c#><.net>" or "|c#|.net|" -> "c# .net"
+pub fn split_tags(tags: &str) -> String {
+  tags.split(['<', '>', '|']).filter(|tag| !tag.is_empty()).collect::<Vec<_>>().join(" ")
+}
+
+// Creates the index, or removes the rows of the site from it when it is
+// shared, and prepares the statement inserting into it the id of a post or
+// comment followed by its indexed columns.
+fn create_index<'a>(connection: &'a Connection, site: &Site, name: &str, columns: &[&str])
+  -> sqlite::Result<Statement<'a>> {
+  let index_name = get_index(site, name);
+  let placeholders = vec!["?"; columns.len()].join(", ");
+  match &site.tables {
+    Tables::Prefixed(_) => {
+      connection.execute(format!("DROP TABLE IF EXISTS [{0}];
+        CREATE VIRTUAL TABLE [{0}] USING fts5({1}, content='', {2});", index_name, columns.join(", "), TOKENIZE))?;
+      connection.prepare(format!("INSERT INTO [{}] (rowid, {}) VALUES (?, {})",
+        index_name, columns.join(", "), placeholders))
+    },
+    Tables::Unified(site_id) => {
+      connection.execute(format!("CREATE VIRTUAL TABLE IF NOT EXISTS [{0}] USING fts5(site_id UNINDEXED, id UNINDEXED, {1}, {2});
+        DELETE FROM [{0}] WHERE site_id = {3};", index_name, columns.join(", "), TOKENIZE, site_id))?;
+      connection.prepare(format!("INSERT INTO [{}] (site_id, id, {}) VALUES ({}, ?, {})",
+        index_name, columns.join(", "), site_id, placeholders))
+    },
+  }
+}
+
+// Removes the indexes of the site, or its rows from the shared ones. They can
+// not be updated: the indexes of a site with tables of its own are contentless.
+pub fn remove(connection: &Connection, site: &Site) -> sqlite::Result<()> {
+  for name in ["Post", "Comment"] {
+    let index_name = get_index(site, name);
+    match &site.tables {
+      Tables::Prefixed(_) => connection.execute(format!("DROP TABLE IF EXISTS [{}];", index_name))?,
+      Tables::Unified(site_id) => if table_exists(connection, &index_name)? {
+        connection.execute(format!("DELETE FROM [{}] WHERE site_id = {};", index_name, site_id))?
+      },
+    }
+  }
+  Ok(())
+}
+
+// Whether the posts of the site are indexed
+pub fn is_indexed(connection: &Connection, site: &Site) -> sqlite::Result<bool> {
+  let index_name = get_index(site, "Post");
+  if !table_exists(connection, &index_name)? {
+    return Ok(false);
+  }
+  match &site.tables {
+    Tables::Prefixed(_) => Ok(true),
+    Tables::Unified(site_id) => {
+      let mut statement = connection.prepare(format!("SELECT id FROM [{}] WHERE site_id = ? LIMIT 1", index_name))?;
+      statement.bind((1, *site_id))?;
+      Ok(statement.next()? == State::Row)
+    },
+  }
+}
+
+// Query selecting the id and the rank (bm25, the lower the better) of the
+// posts of the site matching its only parameter, a FTS5 query. The title and
+// the tags weigh more than the body. Unlike bm25(), the hidden rank column can
+// be used in a join.
+pub fn to_match_query(site: &Site) -> String {
+  let index_name = get_index(site, "Post");
+  match &site.tables {
+    Tables::Prefixed(_) => format!("SELECT rowid AS id, rank FROM [{0}]
+      WHERE [{0}] MATCH ? AND rank MATCH 'bm25(10.0, 1.0, 5.0)'", index_name),
+    Tables::Unified(site_id) => format!("SELECT id, rank FROM [{0}]
+      WHERE [{0}] MATCH ? AND site_id = {1} AND rank MATCH 'bm25(0.0, 0.0, 10.0, 1.0, 5.0)'", index_name, site_id),
+  }
+}
+
+// Indexes the title, body (without its HTML) and tags of the posts.
+pub fn index_posts(connection: &Connection, site: &Site) -> sqlite::Result<()> {
+  let mut insert = create_index(connection, site, "Post", &["title", "body", "tags"])?;
+  let mut select = connection.prepare(format!("SELECT id, title, body, tags FROM [{}] AS p WHERE 1{}",
+    site.table("Post"), site.filter("p")))?;
+  while let State::Row = select.next()? {
+    insert.reset()?;
+    insert.bind((1, select.read::<i64, _>(0)?))?;
+    insert.bind((2, select.read::<Option<String>, _>(1)?.as_deref()))?;
+    insert.bind((3, strip_html(&select.read::<String, _>(2)?).as_str()))?;
+    insert.bind((4, select.read::<Option<String>, _>(3)?.map(|tags| split_tags(&tags)).as_deref()))?;
+    insert.next()?;
+  }
+  Ok(())
+}
+
+// Indexes the text of the comments, which is not HTML.
+pub fn index_comments(connection: &Connection, site: &Site) -> sqlite::Result<()> {
+  let mut insert = create_index(connection, site, "Comment", &["text"])?;
+  let mut select = connection.prepare(format!("SELECT id, text FROM [{}] AS c WHERE 1{}",
+    site.table("Comment"), site.filter("c")))?;
+  while let State::Row = select.next()? {
+    insert.reset()?;
+    insert.bind((1, select.read::<i64, _>(0)?))?;
+    insert.bind((2, select.read::<String, _>(1)?.as_str()))?;
+    insert.next()?;
+  }
+  Ok(())
+}
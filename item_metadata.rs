@@ -0,0 +1,112 @@
+/*
+ * Parsing of the metadata archive.org publishes for an item (here the
+ * stackexchange item), either:
+ * - the JSON returned by https://archive.org/metadata/<item> or
+ * - the `<item>_files.xml` file stored alongside the item files.
+ */
+
+use crate::checksum::{Algorithm, Checksum};
+use serde::Deserialize;
+
+#[derive(Debug, Clone)]
+pub struct ItemFile {
+  pub name: String,
+  pub size: Option<u64>,
+  pub checksum: Option<Checksum>,
+  // Download urls, the preferred one first
+  pub urls: Vec<String>,
+}
+
+// SHA1 is preferred over MD5 when both are present.
+fn get_checksum(md5: Option<String>, sha1: Option<String>) -> Option<Checksum> {
+  sha1.and_then(|digest| Checksum::new(Algorithm::Sha1, &digest).ok())
+    .or_else(|| md5.and_then(|digest| Checksum::new(Algorithm::Md5, &digest).ok()))
+}
+
+// <files>
+//   <file name="unix.stackexchange.com.7z" source="original">
+//     <size>...</size>
+//     <md5>...</md5>
+//     <sha1>...</sha1>
+//     ...
+//   </file>
+// </files>
+#[derive(Debug, Deserialize)]
+struct FilesXmlEntry {
+  #[serde(rename = "@name")]
+  name: String,
+  size: Option<u64>,
+  md5: Option<String>,
+  sha1: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FilesXml {
+  #[serde(default)]
+  file: Vec<FilesXmlEntry>,
+}
+
+// The files.xml does not tell where the files are served from so the urls are
+// built from `base_url` (e.g. https://archive.org/download/stackexchange).
+pub fn from_files_xml(content: &str, base_url: &str) -> Result<Vec<ItemFile>, quick_xml::DeError> {
+  let files: FilesXml = quick_xml::de::from_str(content)?;
+  Ok(files.file.into_iter()
+    .map(|entry| ItemFile {
+      urls: vec![format!("{}/{}", base_url.trim_end_matches('/'), entry.name)],
+      name: entry.name,
+      size: entry.size,
+      checksum: get_checksum(entry.md5, entry.sha1),
+    })
+    .collect())
+}
+
+// {
+//   "server": "ia902605.us.archive.org", "d1": "...", "d2": "...",
+//   "dir": "/0/items/stackexchange",
+//   "files": [{ "name": "...", "size": "123", "md5": "...", "sha1": "...", ... }, ...],
+//   ...
+// }
+#[derive(Debug, Deserialize)]
+struct ItemJsonFile {
+  name: String,
+  size: Option<String>,
+  md5: Option<String>,
+  sha1: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemJson {
+  server: Option<String>,
+  d1: Option<String>,
+  d2: Option<String>,
+  dir: Option<String>,
+  #[serde(default)]
+  files: Vec<ItemJsonFile>,
+}
+
+// The servers listed in the JSON metadata are used as mirrors, `base_url`
+// being the last resort.
+pub fn from_json(content: &str, base_url: &str) -> serde_json::Result<Vec<ItemFile>> {
+  let item: ItemJson = serde_json::from_str(content)?;
+  let mut servers: Vec<String> = Vec::new();
+  for server in [item.server, item.d1, item.d2].into_iter().flatten() {
+    if !servers.contains(&server) {
+      servers.push(server);
+    }
+  }
+  let dir = item.dir.unwrap_or_default();
+  Ok(item.files.into_iter()
+    .map(|file| {
+      let mut urls = servers.iter()
+        .map(|server| format!("https://{}{}/{}", server, dir, file.name))
+        .collect::<Vec<_>>();
+      urls.push(format!("{}/{}", base_url.trim_end_matches('/'), file.name));
+      ItemFile {
+        name: file.name,
+        size: file.size.and_then(|size| size.parse().ok()),
+        checksum: get_checksum(file.md5, file.sha1),
+        urls,
+      }
+    })
+    .collect())
+}
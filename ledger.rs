@@ -0,0 +1,135 @@
+/*
+ * Ledger of the completed stages, kept in the target database so that a rerun
+ * resumes where the previous one stopped.
+ * An entry is identified by the site and the stage ("download", "extract",
+ * "load:<file>" or "fts") and records the archives the stage was completed
+ * with, so that a stage is run again when the archives change. The
+ * "mirror:<archive>" entries record the url an archive was downloaded from
+ * instead. The loads also
+ * record the options the tables were created with (see `load_archives`).
+ * The archives are recorded as "<file>:<size>[:<checksum>]" separated by
+ * spaces (see `same_archives`).
+ */
+
+use sqlite::{Connection, State};
+
+pub const DOWNLOAD: &str = "download";
+pub const EXTRACT: &str = "extract";
+pub const FTS: &str = "fts";
+
+pub fn load_stage(filename: &str) -> String {
+  format!("load:{}", filename)
+}
+
+pub fn mirror_stage(archive: &str) -> String {
+  format!("mirror:{}", archive)
+}
+
+// What the load stages record in place of the archives: the tables are loaded
+// again when the schema options change too (e.g. --unified, --dates).
+pub fn load_archives(archives: &str, schema_key: &str) -> String {
+  format!("{} ({})", archives, schema_key)
+}
+
+// Splits the schema options recorded by the loads from the archives
+fn split_schema_key(archives: &str) -> (&str, Option<&str>) {
+  match archives.rsplit_once(" (") {
+    Some((archives, schema_key)) => (archives, Some(schema_key)),
+    None => (archives, None),
+  }
+}
+
+// Whether the archives recorded in the ledger are the same as `archives`. They
+// are identified by their name and size, their checksums are only compared
+// when both are known: a checksum given later (--checksums) does not make the
+// stages run again. The schema options must be the same.
+pub fn same_archives(recorded: &str, archives: &str) -> bool {
+  let (recorded, recorded_schema_key) = split_schema_key(recorded);
+  let (archives, schema_key) = split_schema_key(archives);
+  let split = |archives: &str| archives.split_whitespace()
+    .map(|archive| archive.splitn(3, ':').map(|field| field.to_string()).collect::<Vec<_>>())
+    .collect::<Vec<_>>();
+  let (recorded, archives) = (split(recorded), split(archives));
+  recorded_schema_key == schema_key && recorded.len() == archives.len()
+    && recorded.iter().zip(archives.iter()).all(|(recorded, archive)| {
+      recorded.get(..2) == archive.get(..2) && match (recorded.get(2), archive.get(2)) {
+        (Some(recorded), Some(archive)) => recorded == archive,
+        _ => true,
+      }
+    })
+}
+
+#[derive(Clone)]
+pub struct Entry {
+  pub site: String,
+  pub stage: String,
+  pub archives: String,
+}
+
+pub fn init(connection: &Connection) -> sqlite::Result<()> {
+  connection.execute("CREATE TABLE IF NOT EXISTS [dlrs_ledger] (
+    site TEXT NOT NULL,
+    stage TEXT NOT NULL,
+    archives TEXT NOT NULL,
+    completed_at TEXT NOT NULL,
+    PRIMARY KEY (site, stage)
+  );")
+}
+
+// Returns the archives the stage was completed with, if it was.
+pub fn get(connection: &Connection, site: &str, stage: &str) -> sqlite::Result<Option<String>> {
+  let mut statement = connection.prepare("SELECT archives FROM [dlrs_ledger] WHERE site = ? AND stage = ?")?;
+  statement.bind((1, site))?;
+  statement.bind((2, stage))?;
+  match statement.next()? {
+    State::Row => Ok(Some(statement.read::<String, _>(0)?)),
+    State::Done => Ok(None),
+  }
+}
+
+pub fn record(connection: &Connection, entry: &Entry) -> sqlite::Result<()> {
+  let mut statement = connection.prepare("INSERT OR REPLACE INTO [dlrs_ledger] (site, stage, archives, completed_at)
+    VALUES (?, ?, ?, datetime('now'))")?;
+  statement.bind((1, entry.site.as_str()))?;
+  statement.bind((2, entry.stage.as_str()))?;
+  statement.bind((3, entry.archives.as_str()))?;
+  statement.next()?;
+  Ok(())
+}
+
+pub fn remove(connection: &Connection, site: &str, stage: &str) -> sqlite::Result<()> {
+  let mut statement = connection.prepare("DELETE FROM [dlrs_ledger] WHERE site = ? AND stage = ?")?;
+  statement.bind((1, site))?;
+  statement.bind((2, stage))?;
+  statement.next()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{load_archives, same_archives};
+
+  #[test]
+  fn archives_are_identified_by_name_and_size() {
+    assert!(same_archives("a.7z:10 b.7z:20", "a.7z:10 b.7z:20"));
+    assert!(same_archives("", ""));
+    assert!(!same_archives("a.7z:10", "a.7z:11"));
+    assert!(!same_archives("a.7z:10", "b.7z:10"));
+    assert!(!same_archives("a.7z:10", "a.7z:10 b.7z:20"));
+  }
+
+  #[test]
+  fn checksums_are_compared_when_both_are_known() {
+    assert!(same_archives("a.7z:10", "a.7z:10:md5:0123"));
+    assert!(same_archives("a.7z:10:md5:0123", "a.7z:10"));
+    assert!(same_archives("a.7z:10:md5:0123", "a.7z:10:md5:0123"));
+    assert!(!same_archives("a.7z:10:md5:0123", "a.7z:10:md5:4567"));
+  }
+
+  #[test]
+  fn loads_compare_the_schema_options() {
+    assert!(same_archives(&load_archives("a.7z:10", "--dates iso"), &load_archives("a.7z:10:md5:0123", "--dates iso")));
+    assert!(!same_archives(&load_archives("a.7z:10", "--dates iso"), &load_archives("a.7z:10", "--dates epoch")));
+    assert!(!same_archives("a.7z:10", &load_archives("a.7z:10", "--dates iso")));
+  }
+}
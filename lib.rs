@@ -0,0 +1,12 @@
+/*
+ * Loading of the site dumps into the database, shared by dlrs and decode.
+ */
+
+pub mod fts;
+pub mod ledger;
+pub mod loader;
+pub mod row_decoder;
+pub mod se_struct;
+pub mod sites;
+pub mod sql_utils;
+pub mod writer;
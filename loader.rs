@@ -0,0 +1,103 @@
+/*
+ * Loading of the XML files of a site dump into the database. Each file holds
+ * the rows of a single table, as empty `<row .../>` elements under a root
+ * element named after the file (`<posts>` in Posts.xml...). The rows are
+ * decoded here and written by the database writer.
+ * Shared by dlrs and decode.
+ */
+
+use crate::row_decoder::RowDecoder;
+use crate::{ledger, se_struct, sql_utils, writer};
+use error_chain::error_chain;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::io::BufRead;
+
+error_chain! {
+  foreign_links {
+    Parser(quick_xml::Error);
+    Deserializer(quick_xml::DeError);
+    SqliteError(sqlite::Error);
+    SqlUtilsError(sql_utils::Error);
+  }
+}
+
+// The files of a site dump, each loaded into its own table.
+pub const SE_FILES: [&str; 8] = ["Badges", "Comments", "PostHistory", "PostLinks", "Posts", "Tags", "Users", "Votes"];
+
+// "posthistory" -> Some("PostHistory.xml")
+pub fn get_se_filename(root: &str) -> Option<String> {
+  SE_FILES.iter().find(|file| file.eq_ignore_ascii_case(root)).map(|file| format!("{}.xml", file))
+}
+
+// Reads the XML declaration and whatever precedes the root element and returns
+// its name. The rows can then be read from `reader`.
+pub fn read_root<R: BufRead>(reader: &mut Reader<R>) -> Result<Option<String>> {
+  let mut buf = Vec::new();
+  loop {
+    match reader.read_event_into(&mut buf)? {
+      Event::Start(e) => return Ok(Some(String::from_utf8_lossy(e.name().as_ref()).to_string())),
+      Event::Eof => return Ok(None),
+      _ => buf.clear(),
+    }
+  }
+}
+
+// The rows are parsed here, in the thread of the caller, and written by the
+// database writer. The table is replaced and the ledger entry recorded once
+// all the rows are written. Returns the number of rows.
+pub fn inject<R: BufRead, T>(writer: &writer::Writer, reader: &mut Reader<R>, table_name: &str,
+  ledger_entry: &ledger::Entry, options: &sql_utils::Options, bulk: bool) -> Result<u64>
+  where T: serde::Serialize + for<'de> serde::Deserialize<'de> {
+  let mut load = writer.load(&ledger_entry.site, table_name);
+  let mut count = 0;
+  let mut decoder = RowDecoder::new();
+  let mut buf = Vec::new();
+  loop {
+    buf.clear();
+    match reader.read_event_into(&mut buf) {
+      Err(e) => error_chain::bail!(
+        "Error at position {}: {:?}",
+        reader.buffer_position(),
+        e
+      ),
+      Ok(Event::Eof) => break,
+      Ok(Event::Empty(e)) => {
+        let tag: T = decoder.decode(&e)?;
+        if count == 0 {
+          let (create_stmt, insert_stmt) = sql_utils::to_init_table::<T>(table_name, options)?;
+          let batch_insert_stmt = if bulk {
+            Some(sql_utils::to_batch_insert_stmt::<T>(table_name, options, writer::MAX_PARAMETERS)?)
+          } else {
+            None
+          };
+          load.begin(sql_utils::to_table_name::<T>(table_name, options)?, create_stmt, insert_stmt, batch_insert_stmt)?;
+        }
+        load.insert(sql_utils::bind_stmt(&tag, options)?)?;
+        count += 1;
+      },
+      _ => (),
+    }
+  }
+
+  load.finish(sql_utils::to_index_stmts::<T>(table_name, options)?, ledger_entry.clone())?;
+  Ok(count)
+}
+
+// Loads an XML file of a site read from `reader` into its table. Files that
+// are not part of the site dump are ignored (None).
+pub fn inject_se_file<R: BufRead>(writer: &writer::Writer, filename: &str, reader: &mut Reader<R>, table_name: &str,
+  ledger_entry: &ledger::Entry, options: &sql_utils::Options, bulk: bool) -> Result<Option<u64>> {
+  let count = match filename {
+    "Badges.xml" => inject::<R, se_struct::Badge>(writer, reader, table_name, ledger_entry, options, bulk),
+    "Comments.xml" => inject::<R, se_struct::Comment>(writer, reader, table_name, ledger_entry, options, bulk),
+    "PostHistory.xml" => inject::<R, se_struct::PostHistory>(writer, reader, table_name, ledger_entry, options, bulk),
+    "PostLinks.xml" => inject::<R, se_struct::PostLink>(writer, reader, table_name, ledger_entry, options, bulk),
+    "Posts.xml" => inject::<R, se_struct::Post>(writer, reader, table_name, ledger_entry, options, bulk),
+    "Tags.xml" => inject::<R, se_struct::Tag>(writer, reader, table_name, ledger_entry, options, bulk),
+    "Users.xml" => inject::<R, se_struct::User>(writer, reader, table_name, ledger_entry, options, bulk),
+    "Votes.xml" => inject::<R, se_struct::Vote>(writer, reader, table_name, ledger_entry, options, bulk),
+    _ => return Ok(None),
+  }?;
+  Ok(Some(count))
+}
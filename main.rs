@@ -3,41 +3,227 @@
 #![feature(let_chains)] // for macro
 
 use bytes::Buf;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use core::convert::Infallible;
+use dlrs::{fts, ledger, loader, se_struct, sites, sql_utils, writer};
+use dlrs::loader::SE_FILES;
 use error_chain::error_chain;
 use futures::StreamExt;
-use quick_xml::events::Event;
-use reqwest::header::{HeaderValue, CONTENT_LENGTH, RANGE};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, ETAG, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER};
 use reqwest::StatusCode;
 use sevenz_rust;
-use std::fs::File;
-use std::io::{BufRead, stdout, Write};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, stdout, Write};
+use std::num::NonZeroUsize;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::path::{Path, PathBuf};
 use sqlite::Connection;
 use tokio;
 
-mod se_struct;
-mod sql_utils;
+mod checksum;
+mod item_metadata;
+mod search;
+mod site_filter;
+mod throttle;
 
 #[derive(Parser, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Config {
+  #[command(subcommand)]
+  command: Option<Command>,
   /// Where to store Stack Exchange files (zipped and unzipped)
-  #[arg(short='f', long, default_value=PathBuf::from("./data").into_os_string(), value_name = "PATH")]
+  #[arg(short='f', long, default_value=PathBuf::from("./data").into_os_string(), value_name = "PATH", global = true)]
   data_path: PathBuf,
   /// List of files/urls to download
-  #[arg(short, long, default_value=PathBuf::from("site.list").into_os_string(), value_name = "FILE")]
+  #[arg(short, long, default_value=PathBuf::from("site.list").into_os_string(), value_name = "FILE", global = true)]
   site_list: PathBuf,
   /// database file
-  #[arg(short, long, default_value=PathBuf::from("dlrs.db").into_os_string(), value_name = "FILE")]
+  #[arg(short, long, default_value=PathBuf::from("dlrs.db").into_os_string(), value_name = "FILE", global = true)]
   database_filename: PathBuf,
   /// Maximum number of parallel threads to use (including max parallel download)
-  #[arg(short, long, default_value_t=3)]
+  #[arg(short, long, default_value_t=3, global = true)]
   max_threads: u8,
+  /// archive.org item metadata file (JSON or `_files.xml`) providing the checksums and sizes of the archives
+  #[arg(short, long, value_name = "FILE", global = true)]
+  checksums: Option<PathBuf>,
+  /// Maximum number of attempts for a request failing with a transient error
+  #[arg(long, default_value_t=5, global = true)]
+  retries: u32,
+  /// Delay before the first retry in milliseconds, doubled after every attempt up to 10 minutes
+  #[arg(long, default_value_t=1000, value_name = "MS", global = true)]
+  retry_delay: u64,
+  /// Number of concurrent connections used to download a single file
+  #[arg(long, default_value_t=1, global = true)]
+  segments: u8,
+  /// Maximum overall download rate in bytes per second (e.g. 500K, 2M)
+  #[arg(long, value_parser = parse_byte_size, value_name = "RATE", global = true)]
+  max_rate: Option<u64>,
+  /// Maximum number of simultaneous connections to the same host
+  #[arg(long, value_name = "N", global = true)]
+  max_host_connections: Option<NonZeroUsize>,
+  /// Base url of a mirror to fall back to when the urls of the site list fail (repeatable)
+  #[arg(long = "mirror", value_name = "URL", global = true)]
+  mirrors: Vec<String>,
+  /// Switch to the next mirror when the download rate stays below this many bytes per second
+  #[arg(long, value_parser = parse_byte_size, value_name = "RATE", global = true)]
+  min_rate: Option<u64>,
+  /// Load the XML files straight from the archives instead of extracting them first
+  #[arg(long, global = true)]
+  stream: bool,
+  /// Only extract and load these tables (e.g. Posts,Tags)
+  #[arg(long, value_delimiter = ',', value_parser = parse_table, value_name = "TABLES", global = true)]
+  tables: Vec<String>,
+  /// How the dates are stored in the database
+  #[arg(long, value_enum, default_value_t = sql_utils::DateFormat::Iso, global = true)]
+  dates: sql_utils::DateFormat,
+  /// Store the enums (post types, vote types...) as integer codes named in lookup tables
+  #[arg(long, global = true)]
+  enum_codes: bool,
+  /// Declare the foreign keys between the tables and index their columns once loaded
+  #[arg(long, global = true)]
+  foreign_keys: bool,
+  /// Store all the sites in shared tables (posts, users...) with a site_id column instead of tables per site
+  #[arg(long, global = true)]
+  unified: bool,
+  /// Load faster at the expense of durability: batched inserts, no disk sync and indexes created at the end
+  #[arg(long, global = true)]
+  bulk: bool,
+  /// Build full-text indexes (FTS5) over the posts and comments once loaded
+  #[arg(long, global = true)]
+  fts: bool,
+  /// Do not display the progress of the jobs, only their errors once done (no terminal needed)
+  #[arg(short, long, global = true)]
+  quiet: bool,
+  #[command(flatten)]
+  filter: site_filter::SiteFilter,
+}
+
+impl Config {
+  // Whether the XML file (e.g. "Posts.xml") is to be extracted and loaded
+  fn is_table_selected(&self, filename: &str) -> bool {
+    let table = filename.strip_suffix(".xml").unwrap_or(filename);
+    self.tables.is_empty() || self.tables.iter().any(|selected| selected == table)
+  }
+
+  fn sql_options(&self) -> sql_utils::Options {
+    sql_utils::Options {
+      dates: self.dates,
+      enum_codes: self.enum_codes,
+      lookup_tables: &se_struct::LOOKUP_TABLES,
+      foreign_keys: if self.foreign_keys { &se_struct::FOREIGN_KEYS } else { &[] },
+      unified: self.unified,
+    }
+  }
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+  /// Download, extract and load the sites (default)
+  All,
+  /// Only download (and verify) the archives
+  Download,
+  /// Only extract the already downloaded archives
+  Extract,
+  /// Only load the already extracted XML files into the database
+  Load,
+  /// Show how far each site of the site list has been processed
+  Status,
+  /// Remove the extracted files and partial downloads
+  Clean(CleanConfig),
+  /// Generate a site list from the archive.org metadata of the stackexchange item
+  Discover(DiscoverConfig),
+  /// Search the questions loaded in the database
+  Search(SearchConfig),
+}
+
+#[derive(Args, Clone)]
+struct CleanConfig {
+  /// Also remove the downloaded archives
+  #[arg(long)]
+  archives: bool,
+}
+
+#[derive(Args, Clone)]
+struct DiscoverConfig {
+  /// Item metadata, JSON (archive.org/metadata/<item>) or `<item>_files.xml`, as an url or a local file
+  #[arg(default_value = "https://archive.org/metadata/stackexchange", value_name = "SOURCE")]
+  source: String,
+  /// Url the item files are served from, used when the metadata does not list any server
+  #[arg(long, default_value = "https://archive.org/download/stackexchange", value_name = "URL")]
+  base_url: String,
+  /// Where to write the site list (standard output by default)
+  #[arg(short, long, value_name = "FILE")]
+  output: Option<PathBuf>,
+}
+
+#[derive(Args, Clone)]
+struct SearchConfig {
+  /// Words to look for in the questions (and, with --fts, in their answers)
+  query: String,
+  /// Only search this site (e.g. unix.stackexchange.com)
+  #[arg(long)]
+  site: Option<String>,
+  /// Only keep the questions with this tag (repeatable)
+  #[arg(long = "tag", value_name = "TAG")]
+  tags: Vec<String>,
+  /// Only keep the questions scored at least this much
+  #[arg(long, allow_negative_numbers = true, value_name = "SCORE")]
+  min_score: Option<i64>,
+  /// Only keep the questions with an accepted answer
+  #[arg(long)]
+  accepted_only: bool,
+  /// Maximum number of questions to show
+  #[arg(short = 'n', long, default_value_t = 10)]
+  limit: usize,
+}
+
+// The stages `process` goes through for each job
+#[derive(Debug, Clone, Copy)]
+struct Stages {
+  download: bool,
+  extract: bool,
+  load: bool,
+}
+
+impl Stages {
+  // When streaming, the archives are loaded without being extracted.
+  fn from_command(command: &Command, stream: bool) -> Stages {
+    match command {
+      Command::Download => Stages { download: true, extract: false, load: false },
+      Command::Extract => Stages { download: false, extract: true, load: false },
+      Command::Load => Stages { download: false, extract: false, load: true },
+      _ => Stages { download: true, extract: !stream, load: true },
+    }
+  }
+}
+
+fn parse_table(s: &str) -> std::result::Result<String, String> {
+  SE_FILES.iter()
+    .find(|table| table.eq_ignore_ascii_case(s))
+    .map(|table| table.to_string())
+    .ok_or_else(|| format!("unknown table {:?}, expected one of {}", s, SE_FILES.join(", ")))
+}
+
+// Parses a positive number of bytes with an optional K, M or G (powers of
+// 1024) suffix.
+fn parse_byte_size(s: &str) -> std::result::Result<u64, String> {
+  let (number, multiplier) = match s.char_indices().last() {
+    Some((index, 'K' | 'k')) => (&s[..index], 1 << 10),
+    Some((index, 'M' | 'm')) => (&s[..index], 1 << 20),
+    Some((index, 'G' | 'g')) => (&s[..index], 1 << 30),
+    _ => (s, 1),
+  };
+  let number = f64::from_str(number).map_err(|_| format!("invalid size {:?}", s))?;
+  let size = number * multiplier as f64;
+  // Also rejects NaN
+  if !(size.is_finite() && size >= 1.0) {
+    return Err(format!("invalid size {:?}, expected at least one byte", s));
+  }
+  Ok(size as u64)
 }
 
 error_chain! {
@@ -48,6 +234,7 @@ error_chain! {
     Parser(quick_xml::Error);
     Deserializer(quick_xml::DeError);
     Decompress(sevenz_rust::Error);
+    Json(serde_json::Error);
     TryFromIntError(core::num::TryFromIntError);
     Infallible(Infallible);
     SystemTimeError(std::time::SystemTimeError);
@@ -55,32 +242,106 @@ error_chain! {
     SqlUtilsError(sql_utils::Error);
     Utf8Error(std::str::Utf8Error);
   }
+  errors {
+    TooBig(size: u64, max_size: u64) {
+      description("site too big")
+      display("site size {} exceeds the maximum size {}", size, max_size)
+    }
+  }
+  links {
+    Loader(loader::Error, loader::ErrorKind);
+  }
 }
 
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
 enum State {
   Error(String),
+  // Dropped by the filters once its size is known (--max-size)
+  Skipped(String),
   Wait,
   Downloading((usize, usize)),
+  Retrying((u32, u32, String)),
+  Verifying(u8),
   Unzipping(u8),
   Parsing((u8, String)),
+  Indexing,
   Done,
 }
 
 #[derive(Debug, Clone)]
-struct Job {
+struct Archive {
+  // Url currently (or last) used to download the file, one of the mirrors
   url: String,
+  mirrors: Vec<String>,
   filepath: String,
+  checksum: Option<checksum::Checksum>,
+  // Size of the archive when known from the item metadata
+  size: Option<u64>,
+}
+
+// A site is usually shipped as a single archive, except stackoverflow.com which
+// is split into one archive per table (stackoverflow.com-Posts.7z, ...).
+#[derive(Debug, Clone)]
+struct Job {
+  // Name of the site, e.g. unix.stackexchange.com
+  site: String,
+  archives: Vec<Archive>,
+  // Index of the archive being downloaded
+  current_archive: usize,
   state: State,
 }
 
+impl Job {
+  fn archive(&self) -> &Archive {
+    &self.archives[self.current_archive]
+  }
+
+  fn archive_mut(&mut self) -> &mut Archive {
+    &mut self.archives[self.current_archive]
+  }
+
+  // Total size of the archives, if they are all known
+  fn size(&self) -> Option<u64> {
+    self.archives.iter().map(|archive| archive.size).sum()
+  }
+
+  // Where the archives of the site are extracted
+  fn data_path(&self) -> PathBuf {
+    let mut data_path = PathBuf::from(&self.archives[0].filepath);
+    data_path.set_file_name(&self.site);
+    data_path
+  }
+
+  // Describes the archives as they are on disk ("<file>:<size>[:<checksum>]"
+  // separated by spaces) or None if one of them is not downloaded.
+  fn archives_on_disk(&self) -> Option<String> {
+    self.archives.iter()
+      .map(|archive| {
+        if !is_downloaded(archive) {
+          return None;
+        }
+        let size = std::fs::metadata(&archive.filepath).ok()?.len();
+        let filename = Path::new(&archive.filepath).file_name()?.to_string_lossy().to_string();
+        Some(match &archive.checksum {
+          Some(checksum) => format!("{}:{}:{}", filename, size, checksum),
+          None => format!("{}:{}", filename, size),
+        })
+      })
+      .collect::<Option<Vec<_>>>()
+      .map(|archives| archives.join(" "))
+  }
+}
+
+// Set by --quiet. The display is updated from everywhere, hence the global.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
 fn update_display(jobs: &Vec<Job>) -> Result<()> {
-  if jobs.len() == 0 {
+  if jobs.len() == 0 || QUIET.load(Ordering::Relaxed) {
     return Ok(())
   }
 
   // This function has no state so we have to recompute a bunch of things every time.
-  let max_filename_length = jobs.iter().map(|job| job.filepath.len()).fold(std::i32::MIN, |a,b| a.max(b as i32));
+  let max_filename_length = jobs.iter().map(|job| job.site.len()).fold(std::i32::MIN, |a,b| a.max(b as i32));
   let terminal_size = crossterm::terminal::size()?;
   let expected_progress_bar_width =
     (terminal_size.0 as usize).saturating_sub(max_filename_length as usize).saturating_sub(30 as usize);
@@ -96,6 +357,7 @@ fn update_display(jobs: &Vec<Job>) -> Result<()> {
         let bvalue = (bdownloaded as f32 / btotal as f32 * 100.0) as u8;
         bvalue.cmp(&avalue)
       },
+      (State::Verifying(avalue), State::Verifying(bvalue)) => bvalue.cmp(&avalue),
       (State::Unzipping(avalue), State::Unzipping(bvalue)) => bvalue.cmp(&avalue),
       (State::Parsing((avalue, _)), State::Parsing((bvalue, _))) => bvalue.cmp(&avalue),
       _ => b.state.cmp(&a.state),
@@ -107,8 +369,7 @@ fn update_display(jobs: &Vec<Job>) -> Result<()> {
   if progress_bar_width > 3 {
     for (index, job) in current_jobs.iter().enumerate() {
       crossterm::execute!(stdout(), crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine))?;
-      let filename = PathBuf::from(job.filepath.clone()).file_name().unwrap().to_str().unwrap().to_string();
-      print!("{:width$} ", filename, width = max_filename_length as usize);
+      print!("{:width$} ", job.site, width = max_filename_length as usize);
       match job.state.clone() {
         State::Wait => print!("{:width$}waiting", "", width = progress_bar_width),
         State::Downloading((downloaded, total)) => {
@@ -116,8 +377,17 @@ fn update_display(jobs: &Vec<Job>) -> Result<()> {
           let nbhash = ((progress_bar_width) as f32 * progress as f32 / 100.0) as u8;
           // ⎯
           let progress_bar = (0..nbhash).map(|_| "━").collect::<String>();
-          print!("[{:width$}] downloading {}% ({}/{})", progress_bar, progress, downloaded, total,
-            width = progress_bar_width);
+          let host = reqwest::Url::parse(&job.archive().url).ok().and_then(|url| url.host_str().map(|host| host.to_string()));
+          print!("[{:width$}] downloading {}% ({}/{}) from {}", progress_bar, progress, downloaded, total,
+            host.unwrap_or_default(), width = progress_bar_width);
+        },
+        State::Retrying((attempt, max_attempts, reason)) => {
+          print!(" {:width$}  retrying ({}/{}): {}", " ", attempt, max_attempts, reason, width = progress_bar_width);
+        },
+        State::Verifying(progress) => {
+          let nbhash = ((progress_bar_width) as f32 * progress as f32 / 100.0) as u8;
+          let progress_bar = (0..nbhash).map(|_| "■").collect::<String>();
+          print!("[{:━<width$}] verifying {}%", progress_bar, progress, width = progress_bar_width);
         },
         State::Unzipping(progress) => {
           let nbhash = ((progress_bar_width) as f32 * progress as f32 / 100.0) as u8;
@@ -129,15 +399,19 @@ fn update_display(jobs: &Vec<Job>) -> Result<()> {
           let progress_bar = (0..nbhash).map(|_| "█").collect::<String>();
           print!("[{:■<width$}] parsing {}% ({})", progress_bar, progress, filename, width = progress_bar_width);
         },
+        State::Indexing => {
+          let full_progress_bar = (0..progress_bar_width).map(|_| "█").collect::<String>();
+          print!("[{:width$}] indexing", full_progress_bar, width = progress_bar_width);
+        },
         State::Done => {
           let full_progress_bar = (0..progress_bar_width).map(|_| "█").collect::<String>();
           print!("[{:width$}] done.", full_progress_bar, width = progress_bar_width);
         },
-        State::Error(label) => {
+        State::Error(label) | State::Skipped(label) => {
           print!(" {:width$}  ", " ", width = progress_bar_width);
           let position = crossterm::cursor::position()?;
           let max: usize = (terminal_size.0).saturating_sub(position.0).saturating_sub(1) as usize;
-          print!("{}", &label[..max]);
+          print!("{}", label.chars().take(max).collect::<String>());
         },
       }
       if index <= current_jobs.len() - 1 {
@@ -159,155 +433,605 @@ fn update_display(jobs: &Vec<Job>) -> Result<()> {
   Ok(())
 }
 
-fn get_data_path(filepath: &Path) -> PathBuf {
-  let filestem = filepath.file_stem().unwrap().to_string_lossy().to_string(); // why does std::path uses OsStr!?
-  let mut output_path: PathBuf = PathBuf::from(filepath.parent().unwrap());
-  output_path.push(filestem);
-  output_path
+// "unix.stackexchange.com.7z" -> "unix.stackexchange.com"
+// "stackoverflow.com-Posts.7z" -> "stackoverflow.com"
+fn get_site_name(filename: &str) -> String {
+  let filestem = Path::new(filename).file_stem().unwrap().to_string_lossy().to_string(); // why does std::path uses OsStr!?
+  match filestem.rsplit_once('-') {
+    Some((site, table)) if SE_FILES.contains(&table) => site.to_string(),
+    _ => filestem,
+  }
+}
+
+// Byte range [start, end) of a file fetched by one connection of a segmented
+// download, and how much of it is already written to disk.
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+  start: usize,
+  end: usize,
+  downloaded: usize,
+}
+
+// Validators of the remote file a partial download was started from. They are
+// stored in a sidecar file next to the download so that an interrupted download
+// is only resumed if the file on the server did not change in the meantime.
+// Segmented downloads also persist the progress of each segment.
+#[derive(Debug, Clone, PartialEq)]
+struct ResumeInfo {
+  content_length: u64,
+  etag: Option<String>,
+  last_modified: Option<String>,
+  segments: Vec<Segment>,
+}
+
+impl ResumeInfo {
+  fn from_headers(headers: &HeaderMap, content_length: u64) -> ResumeInfo {
+    let get = |name| headers.get(name).and_then(|v: &HeaderValue| v.to_str().ok()).map(|v| v.to_string());
+    ResumeInfo { content_length, etag: get(ETAG), last_modified: get(LAST_MODIFIED), segments: Vec::new() }
+  }
+
+  fn same_remote(&self, other: &ResumeInfo) -> bool {
+    self.content_length == other.content_length && self.etag == other.etag && self.last_modified == other.last_modified
+  }
+
+  fn load(path: &Path) -> Option<ResumeInfo> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let mut info = ResumeInfo { content_length: 0, etag: None, last_modified: None, segments: Vec::new() };
+    for line in content.lines() {
+      match line.split_once(": ") {
+        Some(("content-length", value)) => info.content_length = u64::from_str(value).ok()?,
+        Some(("etag", value)) => info.etag = Some(value.to_string()),
+        Some(("last-modified", value)) => info.last_modified = Some(value.to_string()),
+        Some(("segment", value)) => {
+          let values = value.split(' ').map(usize::from_str).collect::<std::result::Result<Vec<_>, _>>().ok()?;
+          if let [start, end, downloaded] = values[..] {
+            info.segments.push(Segment { start, end, downloaded });
+          }
+        },
+        _ => (),
+      }
+    }
+    Some(info)
+  }
+
+  fn save(&self, path: &Path) -> Result<()> {
+    let mut content = format!("content-length: {}\n", self.content_length);
+    if let Some(etag) = &self.etag {
+      content += &format!("etag: {}\n", etag);
+    }
+    if let Some(last_modified) = &self.last_modified {
+      content += &format!("last-modified: {}\n", last_modified);
+    }
+    for segment in &self.segments {
+      content += &format!("segment: {} {} {}\n", segment.start, segment.end, segment.downloaded);
+    }
+    std::fs::write(path, content)?;
+    Ok(())
+  }
+
+  // Value of the If-Range header so that the server sends the whole file
+  // instead of a range if it changed. Weak ETags are not allowed in If-Range.
+  fn if_range(&self) -> Option<&String> {
+    match &self.etag {
+      Some(etag) if !etag.starts_with("W/") => Some(etag),
+      _ => self.last_modified.as_ref(),
+    }
+  }
+}
+
+// What to do with the file already on disk when a download starts
+#[derive(Debug, PartialEq)]
+enum Resume {
+  // The file is complete
+  Done,
+  // Resume the segments of a segmented download
+  Segments(ResumeInfo),
+  // Append to the partial file from this offset
+  From(usize),
+  // Download the whole file again
+  Restart,
+}
+
+// A partial file is only resumed if it was started from the same remote file,
+// otherwise (the file changed, another mirror serves it) it is downloaded
+// again. A segmented download preallocates the file at its full size, so a
+// file of the right size is only complete when no resume file is left.
+fn get_resume(has_resume_file: bool, resume_info: Option<ResumeInfo>, remote_info: &ResumeInfo,
+  file_length: Option<usize>) -> Resume {
+  let content_length = remote_info.content_length as usize;
+  match (resume_info.filter(|resume_info| resume_info.same_remote(remote_info)), file_length) {
+    (_, Some(file_length)) if !has_resume_file && file_length == content_length => Resume::Done,
+    (Some(resume_info), Some(file_length)) if !resume_info.segments.is_empty() && file_length == content_length =>
+      Resume::Segments(resume_info),
+    (Some(resume_info), Some(file_length)) if resume_info.segments.is_empty() && file_length <= content_length =>
+      Resume::From(file_length),
+    _ => Resume::Restart,
+  }
+}
+
+fn get_resume_path(filepath: &str) -> PathBuf {
+  PathBuf::from(format!("{}.resume", filepath))
+}
+
+// Delay requested by the server through the Retry-After header, either in
+// seconds or as an HTTP date.
+fn get_retry_after(headers: &HeaderMap) -> Option<Duration> {
+  let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+  if let Ok(seconds) = u64::from_str(value) {
+    return Some(Duration::from_secs(seconds));
+  }
+  let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+  (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().ok()
+}
+
+// A server that sent nothing for a whole window of the throughput watch, be it
+// the response or the next chunk of its body, is considered stalled. The
+// client has no read timeout of its own, the download would hang otherwise.
+const STALL_TIMEOUT: Duration = ThroughputWatch::WINDOW;
+
+// Reads the response body chunk by chunk so that the bandwidth limit applies
+// while the data is being received.
+async fn read_body(throttle: &throttle::Throttle, mut response: reqwest::Response) -> Result<bytes::Bytes> {
+  let mut body = bytes::BytesMut::new();
+  while let Some(chunk) = tokio::time::timeout(STALL_TIMEOUT, response.chunk()).await
+    .map_err(|_| format!("no data received for {} s", STALL_TIMEOUT.as_secs()))?? {
+    throttle.consume(chunk.len()).await;
+    body.extend_from_slice(&chunk);
+  }
+  Ok(body.freeze())
+}
+
+// Longest wait between two attempts, whether it comes from the backoff or from
+// Retry-After.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+
+// Sends the request and reads the response body. Transient failures (5xx, 429,
+// connection errors, timeouts, interrupted or stalled bodies) are retried with
+// an exponential backoff, honoring Retry-After when the server provides it.
+// The time spent retrying counts in the throughput of the download, so a
+// stalled mirror ends up below --min-rate.
+async fn send_with_retry(config: &Config, throttle: &throttle::Throttle, jobs: &Arc<Mutex<Vec<Job>>>,
+  job_index: usize, request: reqwest::RequestBuilder) -> Result<(StatusCode, HeaderMap, bytes::Bytes)> {
+  let mut attempt = 1;
+  let mut delay = Duration::from_millis(config.retry_delay);
+  loop {
+    let (client, request) = request.try_clone().ok_or("request can not be retried")?.build_split();
+    let request = request?;
+    let permit = throttle.acquire_host(request.url()).await;
+    let (reason, retry_after) = match tokio::time::timeout(STALL_TIMEOUT, client.execute(request)).await {
+      Ok(Ok(response)) => {
+        let status = response.status();
+        let headers = response.headers().clone();
+        if status.is_success() {
+          match read_body(throttle, response).await {
+            Ok(content) => return Ok((status, headers, content)),
+            Err(e) if attempt < config.retries => (e.to_string(), None),
+            Err(e) => return Err(e),
+          }
+        } else if (status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+          || status == StatusCode::REQUEST_TIMEOUT) && attempt < config.retries {
+          (status.to_string(), get_retry_after(&headers))
+        } else {
+          error_chain::bail!("Unexpected server response: {}", status)
+        }
+      },
+      Ok(Err(e)) if (e.is_connect() || e.is_timeout() || e.is_body()) && attempt < config.retries =>
+        (e.to_string(), None),
+      Ok(Err(e)) => return Err(e.into()),
+      Err(_) if attempt < config.retries => (format!("no response for {} s", STALL_TIMEOUT.as_secs()), None),
+      Err(_) => error_chain::bail!("no response for {} s", STALL_TIMEOUT.as_secs()),
+    };
+    drop(permit);
+    jobs.lock().unwrap()[job_index].state = State::Retrying((attempt, config.retries, reason));
+    update_display(&jobs.lock().unwrap())?;
+    tokio::time::sleep(retry_after.unwrap_or(delay).min(MAX_RETRY_DELAY)).await;
+    delay = delay.saturating_mul(2).min(MAX_RETRY_DELAY);
+    attempt += 1;
+  }
+}
+
+// Measures the throughput of a download over windows of a few seconds so that
+// a slow mirror can be abandoned for the next one.
+struct ThroughputWatch {
+  min_rate: Option<u64>,
+  window_start: Instant,
+  window_bytes: usize,
+}
+
+impl ThroughputWatch {
+  const WINDOW: Duration = Duration::from_secs(10);
+
+  fn new(min_rate: Option<u64>) -> ThroughputWatch {
+    ThroughputWatch { min_rate, window_start: Instant::now(), window_bytes: 0 }
+  }
+
+  fn add(&mut self, bytes: usize) -> Result<()> {
+    self.window_bytes += bytes;
+    let elapsed = self.window_start.elapsed();
+    if elapsed >= ThroughputWatch::WINDOW {
+      let rate = (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+      self.window_start = Instant::now();
+      self.window_bytes = 0;
+      if rate < self.min_rate.unwrap_or(0) {
+        error_chain::bail!("download too slow ({} bytes/s)", rate)
+      }
+    }
+    Ok(())
+  }
+}
+
+// Adapt the chunk size to get a display update every seconds ideally
+fn adapt_chunk_size(chunk_size: usize, elapsed: Duration) -> usize {
+  if elapsed.as_millis() > 1000 {
+    std::cmp::max((chunk_size as f32 * 0.7) as usize, 1)
+  } else {
+    (chunk_size as f32 * 1.05) as usize
+  }
+}
+
+fn get_range_request(client: &reqwest::Client, url: &str, remote_info: &ResumeInfo, start: usize, end: usize)
+  -> reqwest::RequestBuilder {
+  let range_header = HeaderValue::from_str(&format!("bytes={}-{}", start, end - 1))
+    .expect("string provided by format!");
+  let mut request = client.get(url).header(RANGE, range_header);
+  if let Some(validator) = remote_info.if_range() {
+    request = request.header(IF_RANGE, validator.as_str());
+  }
+  request
 }
 
-async fn download(_config: Arc<Mutex<Config>>, jobs: &Arc<Mutex<Vec<Job>>>, job_index: usize) -> Result<()> {
+// State shared by the concurrent segments of a segmented download
+struct SegmentedDownload {
+  resume_info: ResumeInfo,
+  watch: ThroughputWatch,
+}
+
+// Downloads the remaining part of one segment of a segmented download, writing
+// it at its position in the preallocated file. The progress of the segment is
+// saved in the resume file after every chunk.
+async fn download_segment(config: &Config, throttle: &throttle::Throttle, jobs: &Arc<Mutex<Vec<Job>>>,
+  job_index: usize, client: &reqwest::Client, shared: &Mutex<SegmentedDownload>, segment_index: usize) -> Result<()> {
   let mut chunk_size: usize = 1024 * 1024;
+  let url = &jobs.lock().unwrap()[job_index].archive().url.clone();
+  let filename = &jobs.lock().unwrap()[job_index].archive().filepath.clone();
+  let resume_path = get_resume_path(filename);
+  let (segment, content_length) = {
+    let resume_info = &shared.lock().unwrap().resume_info;
+    (resume_info.segments[segment_index].clone(), resume_info.content_length as usize)
+  };
+  let mut output_file = OpenOptions::new().write(true).open(filename)?;
+  let mut downloaded = segment.downloaded;
+  while segment.start + downloaded < segment.end {
+    let now = Instant::now();
+    let range_start = segment.start + downloaded;
+    let range_end = std::cmp::min(range_start.saturating_add(chunk_size), segment.end);
+    let request = get_range_request(client, url, &shared.lock().unwrap().resume_info, range_start, range_end);
+    let (status, _, content) = send_with_retry(config, throttle, jobs, job_index, request).await?;
+    if status != StatusCode::PARTIAL_CONTENT {
+      error_chain::bail!("Unexpected server response to a range request: {}", status)
+    }
+
+    output_file.seek(SeekFrom::Start(range_start as u64))?;
+    output_file.write_all(&content[..std::cmp::min(content.len(), segment.end - range_start)])?;
+    downloaded = std::cmp::min(downloaded + content.len(), segment.end - segment.start);
+    let total_downloaded = {
+      let mut shared = shared.lock().unwrap();
+      shared.watch.add(content.len())?;
+      shared.resume_info.segments[segment_index].downloaded = downloaded;
+      shared.resume_info.save(&resume_path)?;
+      shared.resume_info.segments.iter().map(|segment| segment.downloaded).sum()
+    };
+    jobs.lock().unwrap()[job_index].state = State::Downloading((total_downloaded, content_length));
+    update_display(&jobs.lock().unwrap())?;
+    chunk_size = adapt_chunk_size(chunk_size, now.elapsed());
+  }
 
-  let url = &jobs.lock().unwrap()[job_index].url.clone();
-  let filename = &jobs.lock().unwrap()[job_index].filepath.clone();
+  Ok(())
+}
 
-  let client = reqwest::Client::new();
-  // Remotely get the size of the file to download
-  let response = client.head(url).send().await?;
-  let content_length = response
-    .headers()
+// Splits the file into segments fetched concurrently into a preallocated file.
+async fn download_segmented(config: &Config, throttle: &throttle::Throttle, jobs: &Arc<Mutex<Vec<Job>>>,
+  job_index: usize, client: &reqwest::Client, resume_info: ResumeInfo, watch: ThroughputWatch) -> Result<()> {
+  let filename = &jobs.lock().unwrap()[job_index].archive().filepath.clone();
+  let resume_path = get_resume_path(filename);
+  let content_length = resume_info.content_length as usize;
+  let mut resume_info = resume_info;
+  if resume_info.segments.is_empty() {
+    let nb_segments = config.segments as usize;
+    let segment_size = content_length.div_ceil(nb_segments);
+    resume_info.segments = (0..nb_segments)
+      .map(|index| Segment {
+        start: index * segment_size,
+        end: std::cmp::min((index + 1) * segment_size, content_length),
+        downloaded: 0,
+      })
+      .filter(|segment| segment.start < segment.end)
+      .collect();
+    File::create(filename)?.set_len(content_length as u64)?;
+    resume_info.save(&resume_path)?;
+  }
+
+  let downloaded = resume_info.segments.iter().map(|segment| segment.downloaded).sum();
+  jobs.lock().unwrap()[job_index].state = State::Downloading((downloaded, content_length));
+  update_display(&jobs.lock().unwrap())?;
+  let nb_segments = resume_info.segments.len();
+  let shared = Mutex::new(SegmentedDownload { resume_info, watch });
+  futures::future::try_join_all(
+    (0..nb_segments).map(|index| download_segment(config, throttle, jobs, job_index, client, &shared, index))
+  ).await?;
+  std::fs::remove_file(&resume_path)?;
+
+  Ok(())
+}
+
+fn get_content_length(headers: &HeaderMap) -> Result<u64> {
+  let content_length = headers
     .get(CONTENT_LENGTH)
     .ok_or("response doesn't include the content length")?;
-  let content_length = u64::from_str(content_length.to_str()?).map_err(|_| "invalid Content-Length header")?;
-  // Check if the file exists...
-  if let Ok(metadata) = std::fs::metadata(filename) {
-    // ...and if it does, get its size and compare with the size of the file on the server
-    if metadata.len() == content_length {
-      // We assume the file we have is already downloaded and correct.
-      return Ok(());
+  Ok(u64::from_str(content_length.to_str()?).map_err(|_| "invalid Content-Length header")?)
+}
+
+// Sizes of the archives unknown from the site list, asked to the first mirror
+// that answers. The archives already downloaded are measured on disk.
+async fn get_archive_sizes(config: &Config, throttle: &throttle::Throttle, jobs: &Arc<Mutex<Vec<Job>>>,
+  job_index: usize) -> Result<()> {
+  let archives = jobs.lock().unwrap()[job_index].archives.clone();
+  let client = reqwest::Client::new();
+  for (archive_index, archive) in archives.iter().enumerate().filter(|(_, archive)| archive.size.is_none()) {
+    let mut size = None;
+    if is_downloaded(archive) {
+      size = Some(std::fs::metadata(&archive.filepath)?.len());
     }
+    let mut errors = Vec::new();
+    for url in &archive.mirrors {
+      if size.is_some() {
+        break;
+      }
+      match send_with_retry(config, throttle, jobs, job_index, client.head(url)).await
+        .and_then(|(_, headers, _)| get_content_length(&headers)) {
+        Ok(content_length) => size = Some(content_length),
+        Err(e) => errors.push(format!("{}: {}", url, e)),
+      }
+    }
+    jobs.lock().unwrap()[job_index].archives[archive_index].size = Some(size.ok_or(errors.join(", "))?);
   }
-  let mut output_file = std::io::BufWriter::new(File::create(filename)?);
+  Ok(())
+}
 
-  jobs.lock().unwrap()[job_index].state = State::Downloading((0, 0));
-  update_display(&jobs.lock().unwrap())?;
+async fn download(config: Arc<Config>, throttle: Arc<throttle::Throttle>, jobs: &Arc<Mutex<Vec<Job>>>,
+  job_index: usize) -> Result<()> {
+  // The size of the site is usually only known now. It is checked before
+  // anything is downloaded so that a site too big is skipped as a whole.
+  if let Some(max_size) = config.filter.max_size {
+    get_archive_sizes(&config, &throttle, jobs, job_index).await?;
+    let size = jobs.lock().unwrap()[job_index].size().unwrap_or_default();
+    if size > max_size {
+      error_chain::bail!(ErrorKind::TooBig(size, max_size))
+    }
+  }
+  let nb_archives = jobs.lock().unwrap()[job_index].archives.len();
+  for archive_index in 0..nb_archives {
+    jobs.lock().unwrap()[job_index].current_archive = archive_index;
+    download_archive(&config, &throttle, jobs, job_index).await?;
+  }
+  Ok(())
+}
+
+// Downloads the current archive of the job from each mirror in turn until one
+// succeeds. The url of the archive records the mirror that was used, which
+// ends up in the ledger.
+async fn download_archive(config: &Config, throttle: &throttle::Throttle, jobs: &Arc<Mutex<Vec<Job>>>,
+  job_index: usize) -> Result<()> {
+  let mirrors = jobs.lock().unwrap()[job_index].archive().mirrors.clone();
+  let mut errors = Vec::new();
+  for (index, url) in mirrors.iter().enumerate() {
+    jobs.lock().unwrap()[job_index].archive_mut().url = url.clone();
+    // There is no point in abandoning the last mirror because it is slow.
+    let min_rate = if index + 1 < mirrors.len() { config.min_rate } else { None };
+    match download_from_mirror(config, throttle, jobs, job_index, ThroughputWatch::new(min_rate)).await {
+      Ok(()) => return Ok(()),
+      Err(e) => errors.push(format!("{}: {}", url, e)),
+    }
+  }
+  Err(errors.join(", ").into())
+}
+
+async fn download_from_mirror(config: &Config, throttle: &throttle::Throttle, jobs: &Arc<Mutex<Vec<Job>>>,
+  job_index: usize, mut watch: ThroughputWatch) -> Result<()> {
+  let mut chunk_size: usize = 1024 * 1024;
+
+  let url = &jobs.lock().unwrap()[job_index].archive().url.clone();
+  let filename = &jobs.lock().unwrap()[job_index].archive().filepath.clone();
+  let resume_path = get_resume_path(filename);
+
+  let client = reqwest::Client::new();
+  // Remotely get the size of the file to download
+  let (_, headers, _) = send_with_retry(config, throttle, jobs, job_index, client.head(url)).await?;
+  let content_length = get_content_length(&headers)?;
+  let mut remote_info = ResumeInfo::from_headers(&headers, content_length);
+  let mut content_length: usize = content_length.try_into()?;
+  let file_length = std::fs::metadata(filename).ok().map(|metadata| metadata.len() as usize);
   let mut downloaded: usize = 0;
-  while downloaded <= content_length.try_into().unwrap() {
-    let now = Instant::now();
-    let range_end = std::cmp::max(downloaded.saturating_add(chunk_size), content_length.try_into().unwrap());
-    let range_header = HeaderValue::from_str(&format!("bytes={}-{}", downloaded, range_end))
-      .expect("string provided by format!");
-    let response = client.get(url).header(RANGE, range_header).send().await?;
+  match get_resume(resume_path.exists(), ResumeInfo::load(&resume_path), &remote_info, file_length) {
+    Resume::Done => return Ok(()),
+    Resume::Segments(resume_info) =>
+      return download_segmented(config, throttle, jobs, job_index, &client, resume_info, watch).await,
+    Resume::From(file_length) => downloaded = file_length,
+    Resume::Restart => {
+      let accept_ranges = headers.get(ACCEPT_RANGES).and_then(|v| v.to_str().ok());
+      if config.segments > 1 && accept_ranges == Some("bytes") {
+        return download_segmented(config, throttle, jobs, job_index, &client, remote_info, watch).await;
+      }
+    },
+  }
+  let mut output_file = if downloaded > 0 {
+    std::io::BufWriter::new(OpenOptions::new().append(true).open(filename)?)
+  } else {
+    remote_info.save(&resume_path)?;
+    std::io::BufWriter::new(File::create(filename)?)
+  };
 
-    let status = response.status();
+  jobs.lock().unwrap()[job_index].state = State::Downloading((downloaded, content_length));
+  update_display(&jobs.lock().unwrap())?;
+  while downloaded < content_length {
+    let now = Instant::now();
+    let range_end = std::cmp::min(downloaded.saturating_add(chunk_size), content_length);
+    let request = get_range_request(&client, url, &remote_info, downloaded, range_end);
+    let (status, headers, content) = send_with_retry(config, throttle, jobs, job_index, request).await?;
     if !(status == StatusCode::OK || status == StatusCode::PARTIAL_CONTENT) {
       error_chain::bail!("Unexpected server response: {}", status)
     }
 
-    let content = bytes::Bytes::from(response.bytes().await?);
-    // Some server do not honor the range request (like python's SimpleHTTPServer) so we need to
-    // keep track of what is downloaded and stop when we are done.
+    // Some server do not honor the range request (like python's SimpleHTTPServer) or
+    // send the whole file because it changed (If-Range), so we need to start over.
+    // The resume file then describes the file now sent, otherwise the next
+    // resume would send stale validators and start over again.
+    if status == StatusCode::OK {
+      if downloaded != 0 {
+        output_file.flush()?;
+        output_file = std::io::BufWriter::new(File::create(filename)?);
+        downloaded = 0;
+      }
+      content_length = content.len();
+      remote_info = ResumeInfo::from_headers(&headers, content_length as u64);
+      remote_info.save(&resume_path)?;
+    }
+    // We keep track of what is downloaded and stop when we are done.
     downloaded += content.len();
+    watch.add(content.len())?;
     std::io::copy(&mut content.reader(), &mut output_file)?;
-    jobs.lock().unwrap()[job_index].state = State::Downloading((downloaded, content_length.try_into().unwrap()));
+    jobs.lock().unwrap()[job_index].state = State::Downloading((downloaded, content_length));
     update_display(&jobs.lock().unwrap())?;
-    // Adapt the chunk size to get a display update every seconds ideally
-    if now.elapsed().as_millis() > 1000 {
-      chunk_size = (chunk_size as f32 * 0.7) as usize;
-    } else {
-      chunk_size = (chunk_size as f32 * 1.05) as usize;
-    }
+    chunk_size = adapt_chunk_size(chunk_size, now.elapsed());
   }
+  output_file.flush()?;
+  std::fs::remove_file(&resume_path)?;
 
   Ok(())
 }
 
-async fn unzip(_config: Arc<Mutex<Config>>, jobs: &Arc<Mutex<Vec<Job>>>, job_index: usize) -> Result<()> {
-  let filepath = &jobs.lock().unwrap()[job_index].filepath.clone();
-  // https://github.com/dyz1990/sevenz-rust/blob/main/examples/decompress_progress.rs
-  let mut sz = sevenz_rust::SevenZReader::open(filepath, "".into())?;
-  let total_size: u64 = sz
-    .archive()
-    .files
-    .iter()
-    .filter(|e| e.has_stream())
-    .map(|e| e.size())
-    .sum();
-  let mut uncompressed_size = 0;
-  let dest = PathBuf::from(get_data_path(&PathBuf::from(filepath)));
-  sz.for_each_entries(|entry, reader| {
-    let mut buf = vec![0; (total_size as f32 / 100.0) as usize];
-    let unzipped_filename = dest.join(entry.name());
-    std::fs::create_dir_all(unzipped_filename.parent().unwrap()).unwrap();
-    // Check if the file exists
-    if let Ok(metadata) = std::fs::metadata(&unzipped_filename) {
-      // ...and if it does, get its size and compare with the size of the file in the zipped file
-      if metadata.len() == entry.size {
-        // We assume the file we have is already unzipped.
-        return Ok(true);
-      }
-    }
-    let mut file = File::create(unzipped_filename).unwrap();
+// Compare the checksum of the downloaded archives with the expected ones, if any.
+// A corrupt archive is removed so that it is downloaded again on the next run.
+fn verify(_config: Arc<Config>, jobs: &Arc<Mutex<Vec<Job>>>, job_index: usize) -> Result<()> {
+  let archives = jobs.lock().unwrap()[job_index].archives.iter()
+    .filter_map(|archive| Some((archive.filepath.clone(), archive.checksum.clone()?)))
+    .collect::<Vec<_>>();
+  if archives.is_empty() {
+    return Ok(());
+  }
+
+  let mut total_size = 0;
+  for (filepath, _) in &archives {
+    total_size += std::fs::metadata(filepath)?.len();
+  }
+  let mut buf = vec![0; 1024 * 1024];
+  let mut read_total: u64 = 0;
+  jobs.lock().unwrap()[job_index].state = State::Verifying(0);
+  update_display(&jobs.lock().unwrap())?;
+  for (filepath, expected) in archives {
+    let mut file = File::open(&filepath)?;
+    let mut hasher = checksum::Hasher::new(expected.algorithm);
     loop {
-      let read_size = reader.read(&mut buf)?;
+      let read_size = file.read(&mut buf)?;
       if read_size == 0 {
-        break Ok(true);
+        break;
+      }
+      hasher.update(&buf[..read_size]);
+      read_total += read_size as u64;
+      let progress = ((read_total as f32 / total_size as f32) * 100.0) as u8;
+      if jobs.lock().unwrap()[job_index].state != State::Verifying(progress) {
+        jobs.lock().unwrap()[job_index].state = State::Verifying(progress);
+        update_display(&jobs.lock().unwrap())?;
       }
-      file.write_all(&buf[..read_size])?;
-      uncompressed_size += read_size;
-      jobs.lock().unwrap()[job_index].state = State::Unzipping(((uncompressed_size as f32 / total_size as f32) * 100.0) as u8);
-      update_display(&jobs.lock().unwrap()).unwrap(); // TODO: get rid of unwrap
     }
-  })?;
-
+    let digest = hasher.finalize();
+    if digest != expected.digest {
+      drop(file);
+      std::fs::remove_file(&filepath)?;
+      error_chain::bail!("{} mismatch for {}: expected {}, got {} (removed)", expected.algorithm, filepath,
+        expected.digest, digest)
+    }
+  }
   Ok(())
 }
 
-fn inject<R: BufRead, T>(config: Arc<Mutex<Config>>, reader: &mut quick_xml::reader::Reader<R>,
-  table_name: &str) -> Result<()>
-  where T: serde::Serialize + for<'de> serde::Deserialize<'de> {
-  let config = config.lock().unwrap(); // Take a mutex so that database access are not concurrent.
-  let connection = Connection::open(&config.database_filename)?;
-  // println!("BEGIN TRANSACTION; {}", table_name);
-  connection.execute("BEGIN TRANSACTION;")?;
+// Names of the entries to decompress in order to get the wanted ones: the
+// wanted entries themselves and, as the entries of a solid block are compressed
+// in a single stream, the entries preceding them in their block.
+fn get_entries_to_read<F>(archive: &sevenz_rust::Archive, is_wanted: F) -> HashSet<String>
+  where F: Fn(&sevenz_rust::SevenZArchiveEntry) -> bool {
+  let mut to_read = HashSet::new();
+  for (folder_index, folder) in archive.folders.iter().enumerate() {
+    let first = archive.stream_map.folder_first_file_index[folder_index];
+    let files = &archive.files[first..first + folder.num_unpack_sub_streams];
+    if let Some(last) = files.iter().rposition(&is_wanted) {
+      to_read.extend(files[..=last].iter().map(|file| file.name().to_string()));
+    }
+  }
+  to_read
+}
 
-  let mut insert_statement = connection.prepare("")?;
-  let mut count = 0;
-  loop {
-    let mut buf = Vec::new();
-    match reader.read_event_into(&mut buf) {
-      Err(e) => error_chain::bail!(
-        "Error at position {}: {:?}",
-        reader.buffer_position(),
-        e
-      ),
-      Ok(Event::Eof) => break,
-      Ok(Event::Empty(e)) => {
-        let s = format!("<{}/>", std::str::from_utf8(&e)?);
-        // println!("s {}", s);
-        let tag: T = quick_xml::de::from_str(&s)?;
-        if count == 0 {
-          let (create_stmt, insert_stmt) = sql_utils::to_init_table(&tag, table_name)?;
-          // println!("{}", create_stmt);
-          // println!("{}", insert_stmt);
-          connection.execute(create_stmt)?;
-          insert_statement = connection.prepare(insert_stmt)?;
-        }
-        insert_statement.reset()?;
-        let bindings = sql_utils::bind_stmt(&tag)?;
-        for (index, value) in bindings.iter().enumerate() {
-          insert_statement.bind((index + 1, value.as_str()))?;
-        }
-        insert_statement.next()?;
-        count += 1;
-      },
-      _ => (),
+// All the archives of a site are extracted in the same directory.
+async fn unzip(config: Arc<Config>, jobs: &Arc<Mutex<Vec<Job>>>, job_index: usize) -> Result<()> {
+  let filepaths = jobs.lock().unwrap()[job_index].archives.iter()
+    .map(|archive| archive.filepath.clone())
+    .collect::<Vec<_>>();
+  if let Some(filepath) = filepaths.iter().find(|filepath| !Path::new(filepath).exists()) {
+    return Err(format!("{} is not downloaded", filepath))?;
+  }
+  // https://github.com/dyz1990/sevenz-rust/blob/main/examples/decompress_progress.rs
+  let mut szs = filepaths.iter()
+    .map(|filepath| sevenz_rust::SevenZReader::open(filepath, "".into()))
+    .collect::<std::result::Result<Vec<_>, _>>()?;
+  let dest = jobs.lock().unwrap()[job_index].data_path();
+  let is_wanted = |entry: &sevenz_rust::SevenZArchiveEntry| {
+    let filename = Path::new(entry.name()).file_name().unwrap_or_default().to_string_lossy().to_string();
+    if !entry.has_stream() || !config.is_table_selected(&filename) {
+      return false;
     }
+    // Check if the file exists and if it does, compare its size with the size of the file in the zipped file
+    match std::fs::metadata(dest.join(entry.name())) {
+      // We assume the file we have is already unzipped.
+      Ok(metadata) => metadata.len() != entry.size(),
+      Err(_) => true,
+    }
+  };
+  let to_read = szs.iter()
+    .map(|sz| get_entries_to_read(sz.archive(), is_wanted))
+    .collect::<Vec<_>>();
+  let total_size: u64 = szs.iter()
+    .flat_map(|sz| sz.archive().files.iter())
+    .filter(|e| to_read.iter().any(|to_read| to_read.contains(e.name())))
+    .map(|e| e.size())
+    .sum();
+  let mut uncompressed_size = 0;
+  for (sz, to_read) in szs.iter_mut().zip(to_read.iter()) {
+    sz.for_each_entries(|entry, reader| {
+      if !to_read.contains(entry.name()) {
+        return Ok(true);
+      }
+      let mut buf = vec![0; (total_size as usize / 100).clamp(4096, 16 * 1024 * 1024)];
+      let unzipped_filename = dest.join(entry.name());
+      let mut file: Box<dyn Write> = if is_wanted(entry) {
+        std::fs::create_dir_all(unzipped_filename.parent().unwrap()).unwrap();
+        Box::new(File::create(unzipped_filename).unwrap())
+      } else {
+        // Only decompressed to get to the next entries
+        Box::new(std::io::sink())
+      };
+      loop {
+        let read_size = reader.read(&mut buf)?;
+        if read_size == 0 {
+          break Ok(true);
+        }
+        file.write_all(&buf[..read_size])?;
+        uncompressed_size += read_size;
+        jobs.lock().unwrap()[job_index].state = State::Unzipping(((uncompressed_size as f32 / total_size as f32) * 100.0) as u8);
+        update_display(&jobs.lock().unwrap()).unwrap(); // TODO: get rid of unwrap
+      }
+    })?;
   }
 
-  // println!("END TRANSACTION {}", table_name);
-  connection.execute("END TRANSACTION;")?;
   Ok(())
 }
 
@@ -318,63 +1042,281 @@ fn get_site_from_filepath(filepath: &PathBuf) -> Result<String> {
 }
 
 macro_rules! do_load_se_file {
-  ($config:ident, $filename:expr, $t:path, $completion:expr, $jobs:expr, $job_index:expr) => {
-    let mut filepath = get_data_path(&PathBuf::from(&$jobs.lock().unwrap()[$job_index].filepath));
+  ($config:ident, $writer:ident, $filename:expr, $t:path, $completion:expr, $jobs:expr, $job_index:expr, $archives:expr) => {
+    let mut filepath = $jobs.lock().unwrap()[$job_index].data_path();
     filepath.push($filename);
     let sfilepath = filepath.to_string_lossy().to_string();
     $jobs.lock().unwrap()[$job_index].state = State::Parsing(($completion, sfilepath.clone()));
     update_display(&$jobs.lock().unwrap())?;
-    if filepath.exists() {
+    let ledger_entry = ledger::Entry {
+      site: $jobs.lock().unwrap()[$job_index].site.clone(),
+      stage: ledger::load_stage($filename),
+      archives: ledger::load_archives($archives, &$config.sql_options().schema_key()),
+    };
+    if !$config.is_table_selected($filename) {
+      // Not selected with --tables
+    } else if blocking_is_completed($writer, &ledger_entry.site, &ledger_entry.stage, &ledger_entry.archives) {
+      // Already loaded from these archives, with the same schema options
+    } else if filepath.exists() {
       let f = File::open(&sfilepath)?;
       let reader = std::io::BufReader::new(f);
       let mut xmlreader = quick_xml::Reader::from_reader(reader);
       // let foo: $t = quick_xml::de::from_reader(reader)?;
       // Some(foo.row)
       let table_name = &get_site_from_filepath(&filepath)?;
-      inject::<std::io::BufReader<File>, $t>($config.clone(), &mut xmlreader, table_name)?
+      loader::inject::<std::io::BufReader<File>, $t>($writer, &mut xmlreader, table_name, &ledger_entry,
+        &$config.sql_options(), $config.bulk)?;
     } else { /* What to do? */ }
   };
 }
 
-async fn parse(config: Arc<Mutex<Config>>, jobs: &Arc<Mutex<Vec<Job>>>, job_index: usize) -> Result<()> {
-  do_load_se_file!(config, "Badges.xml", se_struct::Badge, 0, jobs, job_index);
-  do_load_se_file!(config, "Comments.xml", se_struct::Comment, 10, jobs, job_index);
-  do_load_se_file!(config, "PostHistory.xml", se_struct::PostHistory, 40, jobs, job_index);
-  do_load_se_file!(config, "PostLinks.xml", se_struct::PostLink, 50, jobs, job_index);
-  do_load_se_file!(config, "Posts.xml", se_struct::Post, 60, jobs, job_index);
-  do_load_se_file!(config, "Tags.xml", se_struct::Tag, 70, jobs, job_index);
-  do_load_se_file!(config, "Users.xml", se_struct::User, 80, jobs, job_index);
-  do_load_se_file!(config, "Votes.xml", se_struct::Vote, 90, jobs, job_index);
+fn parse(config: Arc<Config>, writer: &writer::Writer, jobs: &Arc<Mutex<Vec<Job>>>, job_index: usize,
+  archives: &str) -> Result<()> {
+  do_load_se_file!(config, writer, "Badges.xml", se_struct::Badge, 0, jobs, job_index, archives);
+  do_load_se_file!(config, writer, "Comments.xml", se_struct::Comment, 10, jobs, job_index, archives);
+  do_load_se_file!(config, writer, "PostHistory.xml", se_struct::PostHistory, 40, jobs, job_index, archives);
+  do_load_se_file!(config, writer, "PostLinks.xml", se_struct::PostLink, 50, jobs, job_index, archives);
+  do_load_se_file!(config, writer, "Posts.xml", se_struct::Post, 60, jobs, job_index, archives);
+  do_load_se_file!(config, writer, "Tags.xml", se_struct::Tag, 70, jobs, job_index, archives);
+  do_load_se_file!(config, writer, "Users.xml", se_struct::User, 80, jobs, job_index, archives);
+  do_load_se_file!(config, writer, "Votes.xml", se_struct::Vote, 90, jobs, job_index, archives);
+
+  Ok(())
+}
+
+// Rebuilds the full-text indexes of the site from its loaded tables.
+fn index(writer: &writer::Writer, jobs: &Arc<Mutex<Vec<Job>>>, job_index: usize, archives: &str) -> Result<()> {
+  let (site, data_path) = {
+    let job = &jobs.lock().unwrap()[job_index];
+    (job.site.clone(), job.data_path())
+  };
+  let table_prefix = get_site_from_filepath(&data_path.join("Posts.xml"))?;
+  jobs.lock().unwrap()[job_index].state = State::Indexing;
+  update_display(&jobs.lock().unwrap())?;
+  let entry = ledger::Entry { site, stage: ledger::FTS.to_string(), archives: archives.to_string() };
+  writer.index(&table_prefix, entry)?;
+  Ok(())
+}
+
+// Returns the archives the stage was completed with according to the ledger.
+// The ledger is accessed through the database writer, like the tables in
+// `inject`, and a stage is simply run again if it cannot be read.
+fn blocking_get_ledger_entry(writer: &writer::Writer, site: &str, stage: &str) -> Option<String> {
+  writer.get_ledger_entry(site, stage).ok()?
+}
+
+// The writer may be busy for minutes (indexing, bulk commits, a full queue),
+// so the jobs wait for it on the thread pool of the blocking tasks.
+async fn get_ledger_entry(writer: &writer::Writer, site: &str, stage: &str) -> Option<String> {
+  let (writer, site, stage) = (writer.clone(), site.to_string(), stage.to_string());
+  run_blocking(move || Ok(blocking_get_ledger_entry(&writer, &site, &stage))).await.ok()?
+}
+
+// Whether the stage was completed with these archives according to the ledger
+fn blocking_is_completed(writer: &writer::Writer, site: &str, stage: &str, archives: &str) -> bool {
+  blocking_get_ledger_entry(writer, site, stage).is_some_and(|recorded| ledger::same_archives(&recorded, archives))
+}
+
+async fn is_completed(writer: &writer::Writer, site: &str, stage: &str, archives: &str) -> bool {
+  get_ledger_entry(writer, site, stage).await.is_some_and(|recorded| ledger::same_archives(&recorded, archives))
+}
+
+async fn record_ledger_entry(writer: &writer::Writer, entry: &ledger::Entry) -> Result<()> {
+  let (writer, entry) = (writer.clone(), entry.clone());
+  run_blocking(move || Ok(writer.record_ledger_entry(entry)?)).await
+}
 
+// Same as `parse` but the XML files are read straight from the archives, so
+// that nothing but the database is written to disk.
+fn parse_archives(config: Arc<Config>, writer: &writer::Writer, jobs: &Arc<Mutex<Vec<Job>>>, job_index: usize,
+  archives: &str) -> Result<()> {
+  let (site, filepaths, data_path) = {
+    let job = &jobs.lock().unwrap()[job_index];
+    (job.site.clone(), job.archives.iter().map(|archive| archive.filepath.clone()).collect::<Vec<_>>(), job.data_path())
+  };
+  if let Some(filepath) = filepaths.iter().find(|filepath| !Path::new(filepath).exists()) {
+    error_chain::bail!("{} is not downloaded", filepath)
+  }
+  let mut szs = filepaths.iter()
+    .map(|filepath| sevenz_rust::SevenZReader::open(filepath, "".into()))
+    .collect::<std::result::Result<Vec<_>, _>>()?;
+  let load_archives = ledger::load_archives(archives, &config.sql_options().schema_key());
+  let is_wanted = |entry: &sevenz_rust::SevenZArchiveEntry| {
+    let filename = Path::new(entry.name()).file_name().unwrap_or_default().to_string_lossy().to_string();
+    let stage = ledger::load_stage(&filename);
+    entry.has_stream() && config.is_table_selected(&filename)
+      && !blocking_is_completed(writer, &site, &stage, &load_archives)
+  };
+  let to_read = szs.iter()
+    .map(|sz| get_entries_to_read(sz.archive(), is_wanted))
+    .collect::<Vec<_>>();
+  let wanted = szs.iter()
+    .flat_map(|sz| sz.archive().files.iter())
+    .filter(|e| is_wanted(e))
+    .map(|e| e.name().to_string())
+    .collect::<HashSet<_>>();
+  let total_size: u64 = szs.iter()
+    .flat_map(|sz| sz.archive().files.iter())
+    .filter(|e| to_read.iter().any(|to_read| to_read.contains(e.name())))
+    .map(|e| e.size())
+    .sum();
+  let table_name = get_site_from_filepath(&data_path.join("Posts.xml"))?;
+  let mut uncompressed_size = 0;
+  let mut error: Option<Error> = None;
+  for ((filepath, sz), to_read) in filepaths.iter().zip(szs.iter_mut()).zip(to_read.iter()) {
+    sz.for_each_entries(|entry, reader| {
+      if !to_read.contains(entry.name()) {
+        return Ok(true);
+      }
+      let filename = Path::new(entry.name()).file_name().unwrap_or_default().to_string_lossy().to_string();
+      let ledger_entry = ledger::Entry {
+        site: site.clone(),
+        stage: ledger::load_stage(&filename),
+        archives: load_archives.clone(),
+      };
+      let mut reader = std::io::BufReader::new(reader);
+      if wanted.contains(entry.name()) {
+        let completion = ((uncompressed_size as f32 / total_size as f32) * 100.0) as u8;
+        jobs.lock().unwrap()[job_index].state = State::Parsing((completion, format!("{}:{}", filepath, entry.name())));
+        let result = update_display(&jobs.lock().unwrap())
+          .and_then(|_| loader::inject_se_file(writer, &filename, &mut quick_xml::Reader::from_reader(&mut reader),
+            &table_name, &ledger_entry, &config.sql_options(), config.bulk).map_err(Error::from));
+        if let Err(e) = result {
+          error = Some(e);
+          return Ok(false);
+        }
+      }
+      // The entries of a solid block are compressed in a single stream, each
+      // entry has to be read to the end for the next one to be found.
+      std::io::copy(&mut reader, &mut std::io::sink())?;
+      uncompressed_size += entry.size();
+      Ok(true)
+    })?;
+    if let Some(e) = error.take() {
+      return Err(e);
+    }
+  }
   Ok(())
 }
 
+// Runs `f` on the thread pool of the blocking tasks. The verification, the
+// parsing and the indexing keep a thread busy, and wait for the database
+// writer when its queue is full, which must not hold up the downloads of the
+// other jobs.
+async fn run_blocking<F, T>(f: F) -> Result<T> where F: FnOnce() -> Result<T> + Send + 'static, T: Send + 'static {
+  tokio::task::spawn_blocking(f).await.map_err(|e| Error::from(e.to_string()))?
+}
+
 // Will asynchronously call the various functions of the provided job.
 // It is the responsibility of these function to call update_display regularly.
-async fn process(config: Arc<Mutex<Config>>, jobs: Arc<Mutex<Vec<Job>>>, job_index: usize) -> Result<()> {
-  match download(config.clone(), &jobs, job_index).await {
-    Err(e) => {
-      jobs.lock().unwrap()[job_index].state = State::Error(format!("download error: {}", e));
-      update_display(&jobs.lock().unwrap())?;
-      return Err(e);
-    },
-    _ => (),
+async fn process(config: Arc<Config>, throttle: Arc<throttle::Throttle>, writer: writer::Writer,
+  jobs: Arc<Mutex<Vec<Job>>>, job_index: usize, stages: Stages) -> Result<()> {
+  let site = jobs.lock().unwrap()[job_index].site.clone();
+  let archives = jobs.lock().unwrap()[job_index].archives_on_disk();
+  let downloaded = match &archives {
+    Some(archives) => is_completed(&writer, &site, ledger::DOWNLOAD, archives).await,
+    None => false,
   };
-  match unzip(config.clone(), &jobs, job_index).await {
-    Err(e) => {
-      jobs.lock().unwrap()[job_index].state = State::Error(format!("decompression error: {}", e));
+  if stages.download && !downloaded {
+    match download(config.clone(), throttle, &jobs, job_index).await {
+      // Filtered out like the sites whose size is known from the site list
+      Err(e) if matches!(e.kind(), ErrorKind::TooBig(..)) => {
+        jobs.lock().unwrap()[job_index].state = State::Skipped(format!("skipped: {}", e));
+        update_display(&jobs.lock().unwrap())?;
+        return Ok(());
+      },
+      Err(e) => {
+        jobs.lock().unwrap()[job_index].state = State::Error(format!("download error: {}", e));
+        update_display(&jobs.lock().unwrap())?;
+        return Err(e);
+      },
+      _ => (),
+    };
+    let result = {
+      let (config, jobs) = (config.clone(), jobs.clone());
+      run_blocking(move || verify(config, &jobs, job_index)).await
+    };
+    if let Err(e) = result {
+      jobs.lock().unwrap()[job_index].state = State::Error(format!("checksum error: {}", e));
       update_display(&jobs.lock().unwrap())?;
       return Err(e);
-    },
-    _ => (),
+    }
+    // The mirror of each archive is recorded along with the download
+    let mut entries = jobs.lock().unwrap()[job_index].archives.iter()
+      .map(|archive| ledger::Entry {
+        site: site.clone(),
+        stage: ledger::mirror_stage(&Path::new(&archive.filepath).file_name().unwrap_or_default().to_string_lossy()),
+        archives: archive.url.clone(),
+      })
+      .collect::<Vec<_>>();
+    let archives = jobs.lock().unwrap()[job_index].archives_on_disk().unwrap_or_default();
+    entries.push(ledger::Entry { site: site.clone(), stage: ledger::DOWNLOAD.to_string(), archives });
+    for entry in &entries {
+      if let Err(e) = record_ledger_entry(&writer, entry).await {
+        jobs.lock().unwrap()[job_index].state = State::Error(format!("ledger error: {}", e));
+        update_display(&jobs.lock().unwrap())?;
+        return Err(e);
+      }
+    }
   }
-  match parse(config.clone(), &jobs, job_index).await {
-    Err(e) => {
-      jobs.lock().unwrap()[job_index].state = State::Error(format!("parsing error: {}", e));
-      update_display(&jobs.lock().unwrap())?;
-      return Err(e);
-    },
-    _ => (),
+  // The archives may have been removed once extracted. Archives that are
+  // unknown altogether are recorded as an empty string.
+  let archives_on_disk = jobs.lock().unwrap()[job_index].archives_on_disk();
+  let archives = match archives_on_disk {
+    Some(archives) => Some(archives),
+    None => get_ledger_entry(&writer, &site, ledger::DOWNLOAD).await,
+  }.unwrap_or_default();
+  let data_path = jobs.lock().unwrap()[job_index].data_path();
+  let extracted = data_path.exists()
+    && is_completed(&writer, &site, ledger::EXTRACT, &archives).await;
+  if stages.extract && !extracted {
+    match unzip(config.clone(), &jobs, job_index).await {
+      Err(e) => {
+        jobs.lock().unwrap()[job_index].state = State::Error(format!("decompression error: {}", e));
+        update_display(&jobs.lock().unwrap())?;
+        return Err(e);
+      },
+      _ => (),
+    }
+    // Only part of the archives is extracted when tables are selected
+    let all_tables = config.tables.is_empty();
+    let entry = ledger::Entry { site: site.clone(), stage: ledger::EXTRACT.to_string(), archives: archives.clone() };
+    if all_tables {
+      if let Err(e) = record_ledger_entry(&writer, &entry).await {
+        jobs.lock().unwrap()[job_index].state = State::Error(format!("ledger error: {}", e));
+        update_display(&jobs.lock().unwrap())?;
+        return Err(e);
+      }
+    }
+  }
+  if stages.load {
+    let result = {
+      let (config, writer, jobs, archives) = (config.clone(), writer.clone(), jobs.clone(), archives.clone());
+      run_blocking(move || if config.stream {
+        parse_archives(config, &writer, &jobs, job_index, &archives)
+      } else {
+        parse(config, &writer, &jobs, job_index, &archives)
+      }).await
+    };
+    match result {
+      Err(e) => {
+        jobs.lock().unwrap()[job_index].state = State::Error(format!("parsing error: {}", e));
+        update_display(&jobs.lock().unwrap())?;
+        return Err(e);
+      },
+      _ => (),
+    }
+    if config.fts && !is_completed(&writer, &site, ledger::FTS, &archives).await {
+      let result = {
+        let (writer, jobs, archives) = (writer.clone(), jobs.clone(), archives.clone());
+        run_blocking(move || index(&writer, &jobs, job_index, &archives)).await
+      };
+      if let Err(e) = result {
+        jobs.lock().unwrap()[job_index].state = State::Error(format!("indexing error: {}", e));
+        update_display(&jobs.lock().unwrap())?;
+        return Err(e);
+      }
+    }
   }
 
   jobs.lock().unwrap()[job_index].state = State::Done;
@@ -382,8 +1324,182 @@ async fn process(config: Arc<Mutex<Config>>, jobs: Arc<Mutex<Vec<Job>>>, job_ind
   Ok(())
 }
 
-fn create_job_list(config: &Config, site_list: String) -> Vec<Job> {
-  site_list.lines()
+// An archive is downloaded once it exists without a resume file next to it.
+fn is_downloaded(archive: &Archive) -> bool {
+  Path::new(&archive.filepath).exists() && !get_resume_path(&archive.filepath).exists()
+}
+
+// Prints, for each site, how many archives are downloaded, XML files extracted
+// and tables loaded.
+fn status(config: &Config, jobs: &[Job]) -> Result<()> {
+  let connection = if config.database_filename.exists() {
+    Some(Connection::open(&config.database_filename)?)
+  } else {
+    None
+  };
+  let width = jobs.iter().map(|job| job.site.len()).max().unwrap_or(0);
+  println!("{:width$}  {:>10}  {:>9}  {:>6}", "site", "downloaded", "extracted", "loaded", width = width);
+  for job in jobs {
+    let downloaded = job.archives.iter().filter(|archive| is_downloaded(archive)).count();
+    let data_path = job.data_path();
+    let extracted = SE_FILES.iter()
+      .filter(|file| data_path.join(format!("{}.xml", file)).exists())
+      .count();
+    let mut loaded = 0;
+    if let Some(connection) = &connection {
+      let tables = if config.unified {
+        sites::find_id(connection, &job.site)?.map(sites::Tables::Unified)
+      } else {
+        Some(sites::Tables::Prefixed(get_site_from_filepath(&data_path.join("Posts.xml"))?))
+      };
+      if let Some(tables) = tables {
+        let site = sites::Site { name: job.site.clone(), tables };
+        for table in ["Badge", "Comment", "PostHistory", "PostLink", "Post", "Tag", "User", "Vote"] {
+          if sites::is_loaded(connection, &site, table)? {
+            loaded += 1;
+          }
+        }
+      }
+    }
+    println!("{:width$}  {:>10}  {:>9}  {:>6}", job.site, format!("{}/{}", downloaded, job.archives.len()),
+      format!("{}/{}", extracted, SE_FILES.len()), format!("{}/{}", loaded, SE_FILES.len()), width = width);
+  }
+  Ok(())
+}
+
+// Removes the extracted files and partial downloads of the sites, and the
+// archives if requested.
+fn clean(config: &Config, clean_config: &CleanConfig, jobs: &[Job]) -> Result<()> {
+  let connection = Connection::open(&config.database_filename)?;
+  ledger::init(&connection)?;
+  for job in jobs {
+    ledger::remove(&connection, &job.site, ledger::EXTRACT)?;
+    if clean_config.archives {
+      ledger::remove(&connection, &job.site, ledger::DOWNLOAD)?;
+      for archive in &job.archives {
+        let filename = Path::new(&archive.filepath).file_name().unwrap_or_default().to_string_lossy().to_string();
+        ledger::remove(&connection, &job.site, &ledger::mirror_stage(&filename))?;
+      }
+    }
+    let data_path = job.data_path();
+    if data_path.exists() {
+      std::fs::remove_dir_all(&data_path)?;
+      println!("removed {}", data_path.to_string_lossy());
+    }
+    for archive in &job.archives {
+      let resume_path = get_resume_path(&archive.filepath);
+      let mut to_remove = vec![];
+      if resume_path.exists() {
+        // A partial download is useless without its resume file
+        to_remove.push(resume_path);
+        to_remove.push(PathBuf::from(&archive.filepath));
+      } else if clean_config.archives {
+        to_remove.push(PathBuf::from(&archive.filepath));
+      }
+      for path in to_remove.iter().filter(|path| path.exists()) {
+        std::fs::remove_file(path)?;
+        println!("removed {}", path.to_string_lossy());
+      }
+    }
+  }
+  Ok(())
+}
+
+// Reads archive.org item metadata from an url or a local file, in JSON or XML.
+async fn load_item_metadata(source: &str, base_url: &str) -> Result<Vec<item_metadata::ItemFile>> {
+  let content = if source.contains("://") {
+    reqwest::get(source).await?.error_for_status()?.text().await?
+  } else {
+    std::fs::read_to_string(source)?
+  };
+  if content.trim_start().starts_with('{') {
+    Ok(item_metadata::from_json(&content, base_url)?)
+  } else {
+    Ok(item_metadata::from_files_xml(&content, base_url)?)
+  }
+}
+
+// Writes a site list for the archives of the item that pass the filter.
+async fn discover(config: &DiscoverConfig, filter: &site_filter::SiteFilter) -> Result<()> {
+  let mut files = load_item_metadata(&config.source, &config.base_url).await?;
+  files.retain(|file| file.name.ends_with(".7z"));
+  files.sort_by(|a, b| a.name.cmp(&b.name));
+  // The archives of a split site are kept or dropped together
+  let get_site_size = |site: &str| files.iter()
+    .filter(|file| get_site_name(&file.name) == site)
+    .map(|file| file.size)
+    .sum::<Option<u64>>();
+  let site_list = files.iter()
+    .filter(|file| {
+      let site = get_site_name(&file.name);
+      filter.matches(&site, get_site_size(&site))
+    })
+    .map(|file| {
+      let mut line = format!("{} {}", file.name, file.urls.join(" "));
+      if let Some(checksum) = &file.checksum {
+        line += &format!(" {}", checksum);
+      }
+      line + "\n"
+    })
+    .collect::<String>();
+  match &config.output {
+    Some(output) => std::fs::write(output, site_list)?,
+    None => print!("{}", site_list),
+  }
+  Ok(())
+}
+
+// Prints the questions matching the query, best first, with their accepted
+// answer or, failing that, their best scored answer.
+fn search(config: &Config, search_config: &SearchConfig) -> Result<()> {
+  if !config.database_filename.exists() {
+    error_chain::bail!("database {:?} does not exists", config.database_filename);
+  }
+  let connection = Connection::open(&config.database_filename)?;
+  let mut sites = sites::list(&connection)?;
+  if let Some(site) = &search_config.site {
+    // Either the site (unix.stackexchange.com) or its table prefix (unix.stackexchange)
+    let stem = |name: &str| Path::new(name).file_stem().unwrap_or_default().to_string_lossy().to_string();
+    sites.retain(|listed| listed.name == *site || listed.name == stem(site) || stem(&listed.name) == *site);
+    if sites.is_empty() {
+      error_chain::bail!("site {} is not loaded", site);
+    }
+  }
+  let filter = search::Filter {
+    tags: &search_config.tags,
+    min_score: search_config.min_score,
+    accepted_only: search_config.accepted_only,
+  };
+  let mut hits = Vec::new();
+  for site in &sites {
+    hits.extend(search::search(&connection, site, &search_config.query, &filter, search_config.limit)?);
+  }
+  hits.sort_by(|a, b| a.rank.total_cmp(&b.rank).then(b.score.cmp(&a.score)));
+  hits.truncate(search_config.limit);
+
+  let width = crossterm::terminal::size().map(|size| size.0 as usize).unwrap_or(80).max(40);
+  for hit in &hits {
+    println!("{}", hit.title);
+    let tags = hit.tags.as_deref().map(fts::split_tags).unwrap_or_default();
+    println!("{} #{}, score {}, tags: {}", hit.site.name, hit.id, hit.score, tags);
+    match search::get_answer(&connection, hit)? {
+      Some(answer) => {
+        println!("{} answer, score {}:", if answer.accepted { "Accepted" } else { "Top" }, answer.score);
+        for line in html2text::from_read(answer.body.as_bytes(), width - 2).lines() {
+          println!("  {}", line);
+        }
+      },
+      None => println!("No answer"),
+    }
+    println!();
+  }
+  Ok(())
+}
+
+// Each line of the site list is `<filename> <url> [<url>...] [<checksum>]`. The
+// urls are mirrors of the same file, tried in order, followed by the global mirrors.
+fn create_job_list(config: &Config, site_list: String) -> Result<Vec<Job>> {
+  let archives = site_list.lines()
     .map(|line| line.trim())
     .filter(|line| !line.starts_with('#'))
     .filter(|line| line.len() != 0)
@@ -391,14 +1507,42 @@ fn create_job_list(config: &Config, site_list: String) -> Vec<Job> {
       let split = line.split_whitespace().map(|s| s).collect::<Vec<&str>>();
       let mut filepath = config.data_path.clone();
       filepath.push(split[0].to_string());
-      Job { url: split[1].to_string(), filepath: filepath.to_string_lossy().to_string(), state: State::Wait }
+      let (urls, checksums): (Vec<&str>, Vec<&str>) = split[1..].iter().partition(|s| s.contains("://"));
+      let checksum = match checksums.first() {
+        Some(checksum) => Some(checksum::Checksum::from_str(checksum)?),
+        None => None,
+      };
+      let mirrors = urls.iter()
+        .map(|url| url.to_string())
+        .chain(config.mirrors.iter().map(|base| format!("{}/{}", base.trim_end_matches('/'), split[0])))
+        .collect::<Vec<_>>();
+      let url = mirrors.first().ok_or(format!("no url for {}", split[0]))?.clone();
+      Ok(Archive { url, mirrors, filepath: filepath.to_string_lossy().to_string(), checksum, size: None })
     })
-    .collect()
+    .collect::<Result<Vec<Archive>>>()?;
+
+  // Group the archives of split sites into a single job
+  let mut jobs: Vec<Job> = Vec::new();
+  for archive in archives {
+    let site = get_site_name(&archive.filepath);
+    match jobs.iter_mut().find(|job| job.site == site) {
+      Some(job) => job.archives.push(archive),
+      None => jobs.push(Job { site, archives: vec![archive], current_archive: 0, state: State::Wait }),
+    }
+  }
+  Ok(jobs)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
   let config = Config::parse();
+  let command = config.command.clone().unwrap_or(Command::All);
+  if let Command::Discover(discover_config) = &command {
+    return discover(discover_config, &config.filter).await;
+  }
+  if let Command::Search(search_config) = &command {
+    return search(&config, search_config);
+  }
   if !config.data_path.exists() {
     std::fs::create_dir_all(config.data_path.clone())?;
   }
@@ -406,25 +1550,57 @@ async fn main() -> Result<()> {
     return Err(format!("site list file {:?} does not exists", config.site_list))?;
   }
 
+  let site_list = std::fs::read_to_string(config.site_list.clone())?.parse()?;
+
+  let mut job_list = create_job_list(&config, site_list)?;
+  if let Some(checksums_path) = &config.checksums {
+    let files = load_item_metadata(&checksums_path.to_string_lossy(), "").await?;
+    for archive in job_list.iter_mut().flat_map(|job| job.archives.iter_mut()) {
+      let filename = PathBuf::from(&archive.filepath).file_name().unwrap().to_string_lossy().to_string();
+      if let Some(file) = files.iter().find(|file| file.name == filename) {
+        archive.checksum = archive.checksum.clone().or(file.checksum.clone());
+        archive.size = file.size;
+      }
+    }
+  }
+  // The archives of a split site are kept or dropped together
+  job_list.retain(|job| config.filter.matches(&job.site, job.size()));
+  match &command {
+    Command::Status => return status(&config, &job_list),
+    Command::Clean(clean_config) => return clean(&config, clean_config, &job_list),
+    _ => (),
+  }
+  let stages = Stages::from_command(&command, config.stream);
+
   // Set in Write Ahead Logging to allow simultaneous transactions
   {
     let connection = Connection::open(&config.database_filename)?;
     connection.execute("PRAGMA journal_mode = wal;")?;
+    ledger::init(&connection)?;
+    if stages.load && config.unified {
+      sites::init(&connection)?;
+    }
+    if stages.load && config.enum_codes {
+      for table in &se_struct::LOOKUP_TABLES {
+        connection.execute(sql_utils::to_lookup_table(table))?;
+      }
+    }
   }
 
-  let site_list = std::fs::read_to_string(config.site_list.clone())?.parse()?;
+  QUIET.store(config.quiet, Ordering::Relaxed);
+  if !config.quiet {
+    crossterm::execute!(stdout(), crossterm::cursor::Hide)?;
+    // Restore the cursor on ctrl-c
+    // TODO: Should probably do it in other circumstances
+    ctrlc::set_handler(|| {
+      let _ = crossterm::execute!(stdout(), crossterm::cursor::Show);
+      // We need to force exit here which is what the default handler does.
+      println!("interrupted");
+      std::process::exit(0);
+    }).expect("Error setting Ctrl-C handler");
+  }
 
-  crossterm::execute!(stdout(), crossterm::cursor::Hide)?;
-  // Restore the cursor on ctrl-c
-  // TODO: Should probably do it in other circumstances
-  ctrlc::set_handler(|| {
-    let _ = crossterm::execute!(stdout(), crossterm::cursor::Show);
-    // We need to force exit here which is what the default handler does.
-    println!("interrupted");
-    std::process::exit(0);
-  }).expect("Error setting Ctrl-C handler");
-
-  let jobs = Arc::new(Mutex::new(create_job_list(&config, site_list)));
+  let jobs = Arc::new(Mutex::new(job_list));
   // let jobs = Rc::new(RefCell::new(vec![
   //   Job { url: "http://speedtest.ftp.otenet.gr/files/test100k.db".to_string(), filepath: "test100k.db".to_string(), state: State::Wait },
   //   Job { url: "http://speedtest.ftp.otenet.gr/files/test1Mb.db".to_string(), filepath: "test1Mb.db".to_string(), state: State::Wait },
@@ -444,24 +1620,78 @@ async fn main() -> Result<()> {
   //   tasks.await;
   // }
 
-  {
+  let writer_result = {
     // Here we spawn the jobs for parallel processing
     let max_threads =  config.max_threads;
-    let arc_config = Arc::new(Mutex::new(config));
+    let throttle = Arc::new(throttle::Throttle::new(config.max_rate, config.max_host_connections.map(NonZeroUsize::get)));
+    // The jobs parse their files in parallel, the writer alone writes to the database
+    let (writer, writer_thread) = writer::Writer::spawn(Connection::open(&config.database_filename)?, config.sql_options(),
+      config.bulk);
+    let arc_config = Arc::new(config);
     let mut tokio_jobs = futures::stream::FuturesUnordered::new();
     for index in 0..nbjobs {
-      tokio_jobs.push(tokio::spawn(process(arc_config.clone(), jobs.clone(), index)));
+      tokio_jobs.push(tokio::spawn(process(arc_config.clone(), throttle.clone(), writer.clone(), jobs.clone(), index, stages)));
       if tokio_jobs.len() == max_threads as usize {
         tokio_jobs.next().await;
       }
     }
     while let Some(_) = tokio_jobs.next().await {}
-  }
+    drop(writer);
+    writer_thread.join().map_err(|_| "the database writer panicked")?
+  };
 
-  update_display(&jobs.lock().unwrap())?;
-  let number_of_unfinished_jobs: u16 = jobs.lock().unwrap().iter().filter(|job| job.state != State::Done).count() as u16;
-  crossterm::execute!(stdout(), crossterm::cursor::MoveDown(number_of_unfinished_jobs + 1))?;
-  crossterm::execute!(stdout(), crossterm::cursor::Show)?;
+  if QUIET.load(Ordering::Relaxed) {
+    for job in jobs.lock().unwrap().iter() {
+      if let State::Error(label) | State::Skipped(label) = &job.state {
+        eprintln!("{}: {}", job.site, label);
+      }
+    }
+  } else {
+    update_display(&jobs.lock().unwrap())?;
+    let number_of_unfinished_jobs: u16 = jobs.lock().unwrap().iter().filter(|job| job.state != State::Done).count() as u16;
+    crossterm::execute!(stdout(), crossterm::cursor::MoveDown(number_of_unfinished_jobs + 1))?;
+    crossterm::execute!(stdout(), crossterm::cursor::Show)?;
+  }
+  writer_result?;
   Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::{get_resume, Resume, ResumeInfo, Segment};
+
+  fn remote(etag: &str) -> ResumeInfo {
+    ResumeInfo { content_length: 1000, etag: Some(etag.to_string()), last_modified: None, segments: Vec::new() }
+  }
+
+  fn segmented(etag: &str) -> ResumeInfo {
+    ResumeInfo {
+      segments: vec![Segment { start: 0, end: 500, downloaded: 500 }, Segment { start: 500, end: 1000, downloaded: 10 }],
+      ..remote(etag)
+    }
+  }
+
+  #[test]
+  fn a_file_of_the_right_size_without_resume_file_is_done() {
+    assert_eq!(get_resume(false, None, &remote("a"), Some(1000)), Resume::Done);
+    assert_eq!(get_resume(false, None, &remote("a"), Some(999)), Resume::Restart);
+    assert_eq!(get_resume(false, None, &remote("a"), None), Resume::Restart);
+  }
+
+  #[test]
+  fn resumes_from_the_same_remote_file() {
+    assert_eq!(get_resume(true, Some(segmented("a")), &remote("a"), Some(1000)), Resume::Segments(segmented("a")));
+    assert_eq!(get_resume(true, Some(remote("a")), &remote("a"), Some(400)), Resume::From(400));
+  }
+
+  // The preallocated file of a segmented download has the full size from the
+  // start, it must not be taken for a complete one once the validators change.
+  #[test]
+  fn restarts_when_the_remote_file_changed() {
+    assert_eq!(get_resume(true, Some(segmented("a")), &remote("b"), Some(1000)), Resume::Restart);
+    assert_eq!(get_resume(true, Some(remote("a")), &remote("b"), Some(400)), Resume::Restart);
+    // Unreadable resume file
+    assert_eq!(get_resume(true, None, &remote("a"), Some(1000)), Resume::Restart);
+  }
+}
@@ -0,0 +1,257 @@
+/*
+ * Decoding of the rows of the dumps (`<row Id="1" PostId="2" ... />`) into the
+ * structures of se_struct, straight from the attributes of the element.
+ * The attributes are presented to serde as the fields "@<Name>", the way
+ * quick_xml::de does, so the same structures can be decoded either way. Their
+ * values are only copied when they hold escaped characters or end up in a
+ * String field, and the buffer holding the field names is reused from one row
+ * to the next.
+ */
+
+use quick_xml::events::attributes::Attributes;
+use quick_xml::events::BytesStart;
+use quick_xml::DeError;
+use serde::de::{DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use std::borrow::Cow;
+
+#[derive(Default)]
+pub struct RowDecoder {
+  key: String,
+}
+
+impl RowDecoder {
+  pub fn new() -> RowDecoder {
+    RowDecoder::default()
+  }
+
+  pub fn decode<T: DeserializeOwned>(&mut self, row: &BytesStart) -> Result<T, DeError> {
+    let mut attributes = row.attributes();
+    // The dumps do not repeat attributes, checking it is quadratic
+    attributes.with_checks(false);
+    T::deserialize(RowDeserializer { attributes, key: &mut self.key, value: None })
+  }
+}
+
+struct RowDeserializer<'a> {
+  attributes: Attributes<'a>,
+  key: &'a mut String,
+  // Value of the attribute whose name was last read
+  value: Option<Cow<'a, str>>,
+}
+
+impl<'de, 'a> serde::Deserializer<'de> for RowDeserializer<'a> {
+  type Error = DeError;
+
+  fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+    visitor.visit_map(self)
+  }
+
+  forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+    unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+  }
+}
+
+impl<'de, 'a> MapAccess<'de> for RowDeserializer<'a> {
+  type Error = DeError;
+
+  fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, DeError> {
+    let attribute = match self.attributes.next() {
+      Some(attribute) => attribute?,
+      None => return Ok(None),
+    };
+    self.key.clear();
+    self.key.push('@');
+    self.key.push_str(std::str::from_utf8(attribute.key.as_ref())?);
+    self.value = Some(attribute.unescape_value()?);
+    seed.deserialize(self.key.as_str().into_deserializer()).map(Some)
+  }
+
+  fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeError> {
+    let value = self.value.take().ok_or(DeError::KeyNotRead)?;
+    seed.deserialize(ValueDeserializer(value))
+  }
+}
+
+// Value of an attribute, parsed into whatever the field asks for
+struct ValueDeserializer<'a>(Cow<'a, str>);
+
+macro_rules! deserialize_parsed {
+  ($($method:ident => $visit:ident,)*) => {
+    $(fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+      visitor.$visit(self.0.parse()?)
+    })*
+  };
+}
+
+impl<'de, 'a> serde::Deserializer<'de> for ValueDeserializer<'a> {
+  type Error = DeError;
+
+  fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+    match self.0 {
+      Cow::Borrowed(value) => visitor.visit_str(value),
+      Cow::Owned(value) => visitor.visit_string(value),
+    }
+  }
+
+  // Same spellings as quick_xml::de
+  fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+    match self.0.as_ref() {
+      "true" | "1" | "True" | "TRUE" | "t" | "Yes" | "YES" | "yes" | "y" => visitor.visit_bool(true),
+      "false" | "0" | "False" | "FALSE" | "f" | "No" | "NO" | "no" | "n" => visitor.visit_bool(false),
+      _ => Err(DeError::InvalidBoolean(self.0.into_owned())),
+    }
+  }
+
+  deserialize_parsed! {
+    deserialize_i8 => visit_i8,
+    deserialize_i16 => visit_i16,
+    deserialize_i32 => visit_i32,
+    deserialize_i64 => visit_i64,
+    deserialize_u8 => visit_u8,
+    deserialize_u16 => visit_u16,
+    deserialize_u32 => visit_u32,
+    deserialize_u64 => visit_u64,
+    deserialize_f32 => visit_f32,
+    deserialize_f64 => visit_f64,
+  }
+
+  // An empty attribute is a missing value, as with quick_xml::de
+  fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+    if self.0.is_empty() {
+      visitor.visit_none()
+    } else {
+      visitor.visit_some(self)
+    }
+  }
+
+  fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+    visitor.visit_unit()
+  }
+
+  fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V)
+    -> Result<V::Value, DeError> {
+    visitor.visit_newtype_struct(self)
+  }
+
+  forward_to_deserialize_any! {
+    i128 u128 char str string bytes byte_buf unit_struct seq tuple tuple_struct map struct enum
+    identifier ignored_any
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::RowDecoder;
+  use crate::se_struct;
+  use chrono::NaiveDateTime;
+  use quick_xml::events::Event;
+  use quick_xml::Reader;
+  use serde::Deserialize;
+  use serde_repr::Deserialize_repr;
+
+  #[derive(Debug, Deserialize_repr, PartialEq)]
+  #[repr(u8)]
+  enum Class {
+    Gold = 1,
+    Silver = 2,
+  }
+
+  #[derive(Debug, Deserialize, PartialEq)]
+  struct Row {
+    #[serde(rename = "@Id")]
+    id: u32,
+    #[serde(rename = "@Title")]
+    title: String,
+    #[serde(rename = "@Score")]
+    score: Option<i64>,
+    #[serde(rename = "@Owner")]
+    owner: Option<String>,
+    #[serde(rename = "@Class")]
+    class: Class,
+    #[serde(rename = "@Date")]
+    date: NaiveDateTime,
+    #[serde(rename = "@Deleted")]
+    deleted: Option<bool>,
+  }
+
+  fn decode<T: serde::de::DeserializeOwned>(xml: &str) -> Result<T, quick_xml::DeError> {
+    let mut reader = Reader::from_str(xml);
+    match reader.read_event().unwrap() {
+      Event::Empty(row) => RowDecoder::new().decode(&row),
+      event => panic!("not a row: {:?}", event),
+    }
+  }
+
+  fn date(value: &str) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f").unwrap()
+  }
+
+  #[test]
+  fn decodes_the_attributes() {
+    let row: Row = decode(r#"<row Id="12" Title="Q&amp;A: &lt;b&gt; &quot;quoted&quot; &#233;" Score="-3" Owner="bob" Class="2" Date="2010-07-28T19:04:21.300" Deleted="True" />"#).unwrap();
+    assert_eq!(row, Row {
+      id: 12,
+      title: "Q&A: <b> \"quoted\" é".to_string(),
+      score: Some(-3),
+      owner: Some("bob".to_string()),
+      class: Class::Silver,
+      date: date("2010-07-28T19:04:21.300"),
+      deleted: Some(true),
+    });
+  }
+
+  #[test]
+  fn missing_and_empty_optionals_are_none() {
+    let row: Row = decode(r#"<row Id="1" Title="" Owner="" Class="1" Date="2010-07-28T19:04:21.300" Deleted="" />"#).unwrap();
+    assert_eq!(row.title, "");
+    assert_eq!(row.score, None);
+    assert_eq!(row.owner, None);
+    assert_eq!(row.class, Class::Gold);
+    assert_eq!(row.deleted, None);
+  }
+
+  #[test]
+  fn decodes_the_bool_spellings() {
+    for (value, expected) in [("true", true), ("1", true), ("YES", true), ("y", true), ("False", false), ("0", false),
+      ("no", false), ("f", false)] {
+      let row: Row = decode(&format!(r#"<row Id="1" Title="" Class="1" Date="2010-07-28T19:04:21.300" Deleted="{}" />"#,
+        value)).unwrap();
+      assert_eq!(row.deleted, Some(expected), "{}", value);
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_values() {
+    let rows = [
+      r#"<row Id="1" Title="" Class="1" Date="2010-07-28T19:04:21.300" Deleted="maybe" />"#,
+      r#"<row Id="x" Title="" Class="1" Date="2010-07-28T19:04:21.300" />"#,
+      r#"<row Id="1" Title="" Class="7" Date="2010-07-28T19:04:21.300" />"#,
+      r#"<row Id="1" Title="" Class="1" Date="yesterday" />"#,
+      r#"<row Id="1" Title="" Class="1" />"#,
+      r#"<row Id="1" Title="&unknown;" Class="1" Date="2010-07-28T19:04:21.300" />"#,
+    ];
+    for row in rows {
+      assert!(decode::<Row>(row).is_err(), "{}", row);
+    }
+  }
+
+  // The rows decode as they do with quick_xml::de, which dlrs used before
+  #[test]
+  fn decodes_as_quick_xml_de() {
+    let posts = [
+      r#"<row Id="1" PostTypeId="1" AcceptedAnswerId="3" CreationDate="2010-07-28T19:04:21.300" Score="12" ViewCount="940" Body="&lt;p&gt;How &amp; why?&lt;/p&gt;" OwnerUserId="5" LastEditorUserId="" LastEditDate="2011-01-02T03:04:05.060" LastActivityDate="2012-01-02T03:04:05.000" Title="grep" Tags="&lt;grep&gt;" AnswerCount="2" CommentCount="0" ContentLicense="CC BY-SA 2.5" />"#,
+      r#"<row Id="3" PostTypeId="2" ParentId="1" CreationDate="2010-07-28T19:15:05.000" Score="-1" Body="" OwnerDisplayName="anonymous" LastActivityDate="2010-07-28T19:15:05.000" CommunityOwnedDate="2013-05-06T07:08:09.100" CommentCount="1" ContentLicense="CC BY-SA 3.0" />"#,
+    ];
+    for post in posts {
+      let expected: se_struct::Post = quick_xml::de::from_str(post).unwrap();
+      let decoded: se_struct::Post = decode(post).unwrap();
+      assert_eq!(format!("{:?}", decoded), format!("{:?}", expected));
+    }
+    let badge = r#"<row Id="7" UserId="2" Name="Teacher" Date="2010-07-28T19:04:21.300" Class="3" TagBased="False" />"#;
+    let expected: se_struct::Badge = quick_xml::de::from_str(badge).unwrap();
+    let decoded: se_struct::Badge = decode(badge).unwrap();
+    assert_eq!(format!("{:?}", decoded), format!("{:?}", expected));
+  }
+}
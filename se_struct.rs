@@ -37,6 +37,7 @@ mod naive_date_parser {
 }
 
 use naive_date_parser::from_rfc3339_without_timezone;
+use crate::sql_utils::{serialize_date_time, serialize_optional_date_time, ForeignKey, LookupTable};
 
 #[derive(Debug, Deserialize_repr, Serialize)]
 #[repr(u8)]
@@ -54,7 +55,7 @@ pub struct Badge {
   user_id: String,
   #[serde(rename = "@Name")]
   name: String,
-  #[serde(with = "NaiveDateTime")]
+  #[serde(deserialize_with = "NaiveDateTime::deserialize", serialize_with = "serialize_date_time")]
   #[serde(rename = "@Date")]
   date: NaiveDateTime,
   #[serde(rename = "@Class")]
@@ -81,7 +82,7 @@ pub struct Comment {
   score: i64,
   #[serde(rename = "@Text")]
   text: String,
-  #[serde(with = "NaiveDateTime")]
+  #[serde(deserialize_with = "NaiveDateTime::deserialize", serialize_with = "serialize_date_time")]
   #[serde(rename = "@CreationDate")]
   creation_date: NaiveDateTime,
   // populated if a user has been removed and no longer referenced by user Id
@@ -144,7 +145,7 @@ pub struct PostHistory {
   // At times more than one type of history record can be recorded by a single action.  All of these will be grouped using the same RevisionGUID
   #[serde(rename = "@RevisionGUID")]
   revision_guid: String,
-  #[serde(with = "NaiveDateTime")]
+  #[serde(deserialize_with = "NaiveDateTime::deserialize", serialize_with = "serialize_date_time")]
   #[serde(rename = "@CreationDate")]
   creation_date: NaiveDateTime,
   #[serde(rename = "@UserId")]
@@ -178,7 +179,7 @@ pub enum LinkType {
 pub struct PostLink {
  #[serde(rename = "@Id")]
  id: String,
- #[serde(with = "NaiveDateTime")]
+ #[serde(deserialize_with = "NaiveDateTime::deserialize", serialize_with = "serialize_date_time")]
  #[serde(rename = "@CreationDate")]
  creation_date: NaiveDateTime,
  #[serde(rename = "@PostId")]
@@ -220,12 +221,12 @@ pub struct Post {
   // only present if PostTypeId is 1
   #[serde(rename = "@AcceptedAnswerId")]
   accepted_answer_id: Option<String>,
-  #[serde(with = "NaiveDateTime")]
+  #[serde(deserialize_with = "NaiveDateTime::deserialize", serialize_with = "serialize_date_time")]
   #[serde(rename = "@CreationDate")]
   creation_date: NaiveDateTime,
   // We need `default` to assign None to the option when the field is absent
   // because deserialize_with does not handle this case properly...
-  #[serde(deserialize_with = "from_rfc3339_without_timezone", default)]
+  #[serde(deserialize_with = "from_rfc3339_without_timezone", serialize_with = "serialize_optional_date_time", default)]
   #[serde(rename = "@DeletionDate")]
   deletion_date: Option<NaiveDateTime>,
   #[serde(rename = "@Score")]
@@ -243,10 +244,10 @@ pub struct Post {
   last_editor_user_id: Option<String>,
   #[serde(rename = "@LastEditorDisplayName")]
   last_editor_display_name: Option<String>,
-  #[serde(deserialize_with = "from_rfc3339_without_timezone", default)]
+  #[serde(deserialize_with = "from_rfc3339_without_timezone", serialize_with = "serialize_optional_date_time", default)]
   #[serde(rename = "@LastEditDate")]
   last_edit_date: Option<NaiveDateTime>, // "2009-03-05T22:28:34.823"
-  #[serde(with = "NaiveDateTime")]
+  #[serde(deserialize_with = "NaiveDateTime::deserialize", serialize_with = "serialize_date_time")]
   #[serde(rename = "@LastActivityDate")]
   last_activity_date: NaiveDateTime, // "2009-03-11T12:51:01.480"
   #[serde(rename = "@Title")]
@@ -260,11 +261,11 @@ pub struct Post {
   #[serde(rename = "@FavoriteCount")]
   favorite_count: Option<i64>,
   // populated if the post is closed
-  #[serde(deserialize_with = "from_rfc3339_without_timezone", default)]
+  #[serde(deserialize_with = "from_rfc3339_without_timezone", serialize_with = "serialize_optional_date_time", default)]
   #[serde(rename = "@ClosedDate")]
   closed_date: Option<NaiveDateTime>,
   // populated if post is community wikied
-  #[serde(deserialize_with = "from_rfc3339_without_timezone", default)]
+  #[serde(deserialize_with = "from_rfc3339_without_timezone", serialize_with = "serialize_optional_date_time", default)]
   #[serde(rename = "@CommunityOwnedDate")]
   community_owned_date: Option<NaiveDateTime>,
 }
@@ -301,7 +302,7 @@ pub struct User {
   id: String,
   #[serde(rename = "@Reputation")]
   reputation: i64,
-  #[serde(with = "NaiveDateTime")]
+  #[serde(deserialize_with = "NaiveDateTime::deserialize", serialize_with = "serialize_date_time")]
   #[serde(rename = "@CreationDate")]
   creation_date: NaiveDateTime,
   #[serde(rename = "@DisplayName")]
@@ -310,7 +311,7 @@ pub struct User {
   email_hash: Option<String>,
   #[serde(rename = "@ProfileImageUrl")]
   profile_image_url: Option<String>,
-  #[serde(with = "NaiveDateTime")]
+  #[serde(deserialize_with = "NaiveDateTime::deserialize", serialize_with = "serialize_date_time")]
   #[serde(rename = "@LastAccessDate")]
   last_access_date: NaiveDateTime,
   #[serde(rename = "@WebsiteUrl")]
@@ -364,6 +365,7 @@ pub struct Vote {
  post_id: String,
  #[serde(rename = "@VoteTypeId")]
  vote_type_id: VoteType,
+ #[serde(serialize_with = "serialize_date_time")]
  #[serde(rename = "@CreationDate")]
  creation_date: NaiveDateTime,
  // only for VoteTypeId 5
@@ -378,3 +380,61 @@ pub struct Vote {
 pub struct Votes {
   pub row: Vec<Vote>,
 }
+
+// Builds the lookup table of an enum. The match makes sure that no variant is
+// forgotten.
+macro_rules! lookup_table {
+  ($enum:ident, $table_name:expr, [$($variant:ident),* $(,)?]) => {{
+    const _: fn(&$enum) = |value| match value { $($enum::$variant => ()),* };
+    LookupTable {
+      enum_name: stringify!($enum),
+      table_name: $table_name,
+      variants: &[$(($enum::$variant as i64, stringify!($variant))),*],
+    }
+  }};
+}
+
+// post_history_type_id stays a plain integer, new types keep being added to
+// the dumps, but post_history_types names the known ones as in SEDE.
+pub const LOOKUP_TABLES: [LookupTable; 5] = [
+  lookup_table!(BadgeClass, "badge_classes", [Gold, Silver, Bronze]),
+  lookup_table!(PostHistoryType, "post_history_types", [
+    InitialTitle, InitialBody, InitialTags, EditTitle, EditBody, EditTags, RollbackTitle, RollbackBody,
+    RollbackTags, PostClosed, PostReopened, PostDeleted, PostUndeleted, PostLocked, PostUnlocked,
+    CommunityOwned, PostMigrated, QuestionMerged, QuestionProtected, QuestionUnprotected, PostDisassociated,
+    QuestionUnmerged, SuggestedEditApplied, PostTweeted, MovedToChat, PostNoticeAdded, PostNoticeRemoved,
+    PostMigratedAway, PostMigratedHere, PostMergeSource, PostMergeDestination,
+  ]),
+  lookup_table!(LinkType, "link_types", [Linked, Duplicate]),
+  lookup_table!(PostType, "post_types", [
+    Question, Answer, Wiki, TagWikiExcerpt, TagWiki, ModeratorNomination, WikiPlaceholder, PrivilegeWiki,
+  ]),
+  lookup_table!(VoteType, "vote_types", [
+    AcceptedByOriginator, UpMod, DownMod, Offensive, Favorite, Close, Reopen, BountyStart, BountyClose,
+    Deletion, Undeletion, Spam, InformModerator, ModeratorReview, ApproveEditSuggestion,
+  ]),
+];
+
+macro_rules! foreign_key {
+  ($table:ident . $column:ident -> $references:ident) => {
+    ForeignKey { table: stringify!($table), column: stringify!($column), references: stringify!($references) }
+  };
+}
+
+pub const FOREIGN_KEYS: [ForeignKey; 15] = [
+  foreign_key!(Badge.user_id -> User),
+  foreign_key!(Comment.post_id -> Post),
+  foreign_key!(Comment.user_id -> User),
+  foreign_key!(PostHistory.post_id -> Post),
+  foreign_key!(PostHistory.user_id -> User),
+  foreign_key!(PostLink.post_id -> Post),
+  foreign_key!(PostLink.related_post_id -> Post),
+  foreign_key!(Post.parent_id -> Post),
+  foreign_key!(Post.accepted_answer_id -> Post),
+  foreign_key!(Post.owner_user_id -> User),
+  foreign_key!(Post.last_editor_user_id -> User),
+  foreign_key!(Tag.excerpt_post_id -> Post),
+  foreign_key!(Tag.wiki_post_id -> Post),
+  foreign_key!(Vote.post_id -> Post),
+  foreign_key!(Vote.user_id -> User),
+];
@@ -0,0 +1,135 @@
+/*
+ * Search of the questions loaded in the database. The questions are matched
+ * through the full-text index of their site when it was built (--fts), on
+ * their own text or on the text of one of their answers, and ranked by
+ * relevance. Without index, every word of the query has to appear in the
+ * title or the body of the question and the questions are ranked by score.
+ */
+
+use dlrs::fts;
+use dlrs::sites::Site;
+use sqlite::{Connection, State, Value};
+
+pub struct Filter<'a> {
+  pub tags: &'a [String],
+  pub min_score: Option<i64>,
+  pub accepted_only: bool,
+}
+
+pub struct Hit {
+  pub site: Site,
+  pub id: i64,
+  pub title: String,
+  pub score: i64,
+  pub tags: Option<String>,
+  pub accepted_answer_id: Option<i64>,
+  // bm25 of the best matching post (the lower the better), 0 without index
+  pub rank: f64,
+}
+
+pub struct Answer {
+  pub body: String,
+  pub score: i64,
+  pub accepted: bool,
+}
+
+// Every word is quoted so that FTS5 does not read it as an operator or a
+// column filter (e.g. "c#", "-r", "a:b"). The words are implicitly ANDed.
+fn to_fts_query(query: &str) -> String {
+  query.split_whitespace()
+    .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+// Conditions on the question `q` of the hits, and their parameters
+fn to_conditions(filter: &Filter) -> (String, Vec<Value>) {
+  // Enums are stored by name or, with --enum-codes, by code
+  let mut conditions = String::from("q.post_type_id IN ('Question', 1)");
+  let mut parameters = Vec::new();
+  if let Some(min_score) = filter.min_score {
+    conditions += " AND q.score >= ?";
+    parameters.push(Value::Integer(min_score));
+  }
+  if filter.accepted_only {
+    conditions += " AND q.accepted_answer_id IS NOT NULL";
+  }
+  // Tags are stored as "<a><b>" or, in recent dumps, as "|a|b|"
+  for tag in filter.tags {
+    conditions += " AND (q.tags LIKE ? OR q.tags LIKE ?)";
+    parameters.push(Value::String(format!("%<{}>%", tag)));
+    parameters.push(Value::String(format!("%|{}|%", tag)));
+  }
+  (conditions, parameters)
+}
+
+pub fn search(connection: &Connection, site: &Site, query: &str, filter: &Filter, limit: usize)
+  -> sqlite::Result<Vec<Hit>> {
+  let (conditions, mut parameters) = to_conditions(filter);
+  let conditions = conditions + &site.filter("q");
+  // An empty query matches every question, which the index cannot do
+  let sql = if !query.trim().is_empty() && fts::is_indexed(connection, site)? {
+    parameters.insert(0, Value::String(to_fts_query(query)));
+    format!("SELECT q.id, q.title, q.score, q.tags, CAST(q.accepted_answer_id AS INTEGER), min(m.rank) AS rank
+      FROM ({0}) AS m
+      JOIN [{1}] AS hit ON hit.id = m.id{2}
+      JOIN [{1}] AS q ON q.id = CAST(coalesce(hit.parent_id, hit.id) AS INTEGER)
+      WHERE {3}
+      GROUP BY q.id ORDER BY rank, q.score DESC LIMIT {4}",
+      fts::to_match_query(site), site.table("Post"), site.filter("hit"), conditions, limit)
+  } else {
+    let mut conditions = conditions;
+    for word in query.split_whitespace() {
+      conditions += " AND (q.title LIKE ? OR q.body LIKE ?)";
+      parameters.push(Value::String(format!("%{}%", word)));
+      parameters.push(Value::String(format!("%{}%", word)));
+    }
+    format!("SELECT q.id, q.title, q.score, q.tags, CAST(q.accepted_answer_id AS INTEGER), 0.0 AS rank
+      FROM [{}] AS q
+      WHERE {}
+      ORDER BY q.score DESC LIMIT {}", site.table("Post"), conditions, limit)
+  };
+  let mut statement = connection.prepare(sql)?;
+  statement.bind(&parameters[..])?;
+  let mut hits = Vec::new();
+  while let State::Row = statement.next()? {
+    hits.push(Hit {
+      site: site.clone(),
+      id: statement.read::<i64, _>(0)?,
+      title: statement.read::<Option<String>, _>(1)?.unwrap_or_default(),
+      score: statement.read::<i64, _>(2)?,
+      tags: statement.read::<Option<String>, _>(3)?,
+      accepted_answer_id: statement.read::<Option<i64>, _>(4)?,
+      rank: statement.read::<f64, _>(5)?,
+    });
+  }
+  Ok(hits)
+}
+
+// Returns the accepted answer of the question or, failing that, its best
+// scored answer.
+pub fn get_answer(connection: &Connection, hit: &Hit) -> sqlite::Result<Option<Answer>> {
+  let mut statement = match hit.accepted_answer_id {
+    Some(accepted_answer_id) => {
+      let mut statement = connection.prepare(format!("SELECT body, score FROM [{}] AS a WHERE id = ?{}",
+        hit.site.table("Post"), hit.site.filter("a")))?;
+      statement.bind((1, accepted_answer_id))?;
+      statement
+    },
+    None => {
+      let mut statement = connection.prepare(format!(
+        "SELECT body, score FROM [{}] AS a WHERE parent_id = ?{} ORDER BY score DESC LIMIT 1",
+        hit.site.table("Post"), hit.site.filter("a")))?;
+      statement.bind((1, hit.id))?;
+      statement
+    },
+  };
+  match statement.next()? {
+    State::Row => Ok(Some(Answer {
+      body: statement.read::<String, _>(0)?,
+      score: statement.read::<i64, _>(1)?,
+      accepted: hit.accepted_answer_id.is_some(),
+    })),
+    State::Done => Ok(None),
+  }
+}
@@ -0,0 +1,98 @@
+/*
+ * Command line filters selecting which sites are processed.
+ */
+
+use clap::Args;
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct SiteFilter {
+  /// Only keep the sites matching this glob pattern (repeatable, e.g. "*.stackexchange.com")
+  #[arg(long = "include", value_name = "GLOB", global = true)]
+  pub include: Vec<glob::Pattern>,
+  /// Drop the sites matching this glob pattern (repeatable)
+  #[arg(long = "exclude", value_name = "GLOB", global = true)]
+  pub exclude: Vec<glob::Pattern>,
+  /// Drop the meta sites
+  #[arg(long, global = true)]
+  pub no_meta: bool,
+  /// Drop the sites whose archives add up to more than this size (e.g. 500M, 2G)
+  #[arg(long, value_parser = crate::parse_byte_size, value_name = "SIZE", global = true)]
+  pub max_size: Option<u64>,
+}
+
+impl SiteFilter {
+  // Patterns are matched against the name of the site, e.g. "stackoverflow.com"
+  // for all its archives (stackoverflow.com-Posts.7z...), and the size is the
+  // size of all its archives. An unknown size is never filtered out.
+  pub fn matches(&self, site: &str, size: Option<u64>) -> bool {
+    let is_match = |pattern: &glob::Pattern| pattern.matches(site);
+    if self.no_meta && (site.starts_with("meta.") || site.contains(".meta.")) {
+      return false;
+    }
+    if !self.include.is_empty() && !self.include.iter().any(is_match) {
+      return false;
+    }
+    if self.exclude.iter().any(is_match) {
+      return false;
+    }
+    match (self.max_size, size) {
+      (Some(max_size), Some(size)) => size <= max_size,
+      _ => true,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::SiteFilter;
+
+  fn patterns(patterns: &[&str]) -> Vec<glob::Pattern> {
+    patterns.iter().map(|pattern| glob::Pattern::new(pattern).unwrap()).collect()
+  }
+
+  #[test]
+  fn keeps_everything_by_default() {
+    let filter = SiteFilter::default();
+    assert!(filter.matches("unix.stackexchange.com", Some(u64::MAX)));
+    assert!(filter.matches("meta.stackoverflow.com", None));
+  }
+
+  #[test]
+  fn matches_the_site_name() {
+    let filter = SiteFilter { include: patterns(&["unix.*"]), ..SiteFilter::default() };
+    assert!(filter.matches("unix.stackexchange.com", None));
+    assert!(!filter.matches("askubuntu.com", None));
+    let filter = SiteFilter { include: patterns(&["stackoverflow.com"]), ..SiteFilter::default() };
+    assert!(filter.matches("stackoverflow.com", None));
+    assert!(!filter.matches("meta.stackoverflow.com", None));
+  }
+
+  #[test]
+  fn exclude_wins_over_include() {
+    let filter = SiteFilter {
+      include: patterns(&["*.stackexchange.com"]),
+      exclude: patterns(&["unix.*", "tex.*"]),
+      ..SiteFilter::default()
+    };
+    assert!(filter.matches("math.stackexchange.com", None));
+    assert!(!filter.matches("unix.stackexchange.com", None));
+    assert!(!filter.matches("tex.stackexchange.com", None));
+  }
+
+  #[test]
+  fn drops_the_meta_sites() {
+    let filter = SiteFilter { no_meta: true, ..SiteFilter::default() };
+    assert!(!filter.matches("meta.stackoverflow.com", None));
+    assert!(!filter.matches("unix.meta.stackexchange.com", None));
+    assert!(filter.matches("unix.stackexchange.com", None));
+    assert!(filter.matches("metatrader.stackexchange.com", None));
+  }
+
+  #[test]
+  fn drops_the_sites_too_big_unless_their_size_is_unknown() {
+    let filter = SiteFilter { max_size: Some(1000), ..SiteFilter::default() };
+    assert!(filter.matches("unix.stackexchange.com", Some(1000)));
+    assert!(!filter.matches("unix.stackexchange.com", Some(1001)));
+    assert!(filter.matches("unix.stackexchange.com", None));
+  }
+}
@@ -0,0 +1,117 @@
+/*
+ * Where the rows of a site are stored: either in tables of its own, named
+ * after the site (`<prefix>_Post`...), or, with --unified, in the tables
+ * shared by all the sites (`posts`...) in which they are told apart by a
+ * site_id column referencing the `sites` table.
+ */
+
+use crate::sql_utils::{to_unified_table_name, SITE_TABLE};
+use sqlite::{Connection, State};
+
+#[derive(Clone, Debug)]
+pub enum Tables {
+  // Prefix of the tables of the site (e.g. "unix.stackexchange")
+  Prefixed(String),
+  // Id of the site in the shared tables
+  Unified(i64),
+}
+
+#[derive(Clone, Debug)]
+pub struct Site {
+  // Table prefix or, in the shared tables, site name (e.g. "unix.stackexchange.com")
+  pub name: String,
+  pub tables: Tables,
+}
+
+impl Site {
+  // Name of the table storing the structures named `name` (e.g. "Post")
+  pub fn table(&self, name: &str) -> String {
+    match &self.tables {
+      Tables::Prefixed(prefix) => format!("{}_{}", prefix, name),
+      Tables::Unified(_) => to_unified_table_name(name),
+    }
+  }
+
+  // Condition restricting the rows of `alias` to the site, to append to a
+  // WHERE clause or a join constraint
+  pub fn filter(&self, alias: &str) -> String {
+    match &self.tables {
+      Tables::Prefixed(_) => String::new(),
+      Tables::Unified(site_id) => format!(" AND {}.site_id = {}", alias, site_id),
+    }
+  }
+}
+
+pub fn table_exists(connection: &Connection, table_name: &str) -> sqlite::Result<bool> {
+  let mut statement = connection.prepare("SELECT name FROM sqlite_master WHERE name = ?")?;
+  statement.bind((1, table_name))?;
+  Ok(statement.next()? == State::Row)
+}
+
+// Whether structures named `name` (e.g. "Post") of the site are loaded
+pub fn is_loaded(connection: &Connection, site: &Site, name: &str) -> sqlite::Result<bool> {
+  if !table_exists(connection, &site.table(name))? {
+    return Ok(false);
+  }
+  match &site.tables {
+    Tables::Prefixed(_) => Ok(true),
+    Tables::Unified(site_id) => {
+      let mut statement = connection.prepare(format!("SELECT id FROM [{}] WHERE site_id = ? LIMIT 1", site.table(name)))?;
+      statement.bind((1, *site_id))?;
+      Ok(statement.next()? == State::Row)
+    },
+  }
+}
+
+pub fn init(connection: &Connection) -> sqlite::Result<()> {
+  connection.execute(format!("CREATE TABLE IF NOT EXISTS [{}] (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+  );", SITE_TABLE))
+}
+
+// Returns the id of the site in the shared tables, if it is registered.
+pub fn find_id(connection: &Connection, name: &str) -> sqlite::Result<Option<i64>> {
+  if !table_exists(connection, SITE_TABLE)? {
+    return Ok(None);
+  }
+  let mut statement = connection.prepare(format!("SELECT id FROM [{}] WHERE name = ?", SITE_TABLE))?;
+  statement.bind((1, name))?;
+  match statement.next()? {
+    State::Row => Ok(Some(statement.read::<i64, _>(0)?)),
+    State::Done => Ok(None),
+  }
+}
+
+// Returns the id of the site in the shared tables, registering it if needed.
+pub fn get_id(connection: &Connection, name: &str) -> sqlite::Result<i64> {
+  let mut statement = connection.prepare(format!("INSERT OR IGNORE INTO [{}] (name) VALUES (?)", SITE_TABLE))?;
+  statement.bind((1, name))?;
+  statement.next()?;
+  let mut statement = connection.prepare(format!("SELECT id FROM [{}] WHERE name = ?", SITE_TABLE))?;
+  statement.bind((1, name))?;
+  statement.next()?;
+  statement.read::<i64, _>(0)
+}
+
+// Returns the sites whose posts are loaded, in their own tables or in the
+// shared ones.
+pub fn list(connection: &Connection) -> sqlite::Result<Vec<Site>> {
+  let mut sites = Vec::new();
+  let mut statement = connection.prepare(
+    "SELECT name FROM sqlite_master WHERE type = 'table' AND name LIKE '%\\_Post' ESCAPE '\\' ORDER BY name")?;
+  while let State::Row = statement.next()? {
+    let name = statement.read::<String, _>(0)?;
+    let prefix = name.trim_end_matches("_Post").to_string();
+    sites.push(Site { name: prefix.clone(), tables: Tables::Prefixed(prefix) });
+  }
+  let post_table = to_unified_table_name("Post");
+  if table_exists(connection, &post_table)? {
+    let mut statement = connection.prepare(format!(
+      "SELECT id, name FROM [{}] WHERE id IN (SELECT DISTINCT site_id FROM [{}]) ORDER BY name", SITE_TABLE, post_table))?;
+    while let State::Row = statement.next()? {
+      sites.push(Site { name: statement.read::<String, _>(1)?, tables: Tables::Unified(statement.read::<i64, _>(0)?) });
+    }
+  }
+  Ok(sites)
+}
@@ -6,10 +6,15 @@
  * - one that generates a CREATE TABLE request and an INSERT request statement and
  * - one that binds the insert statement with values from the structure.
  * for any "serde" serializable structure.
+ *
+ * The CREATE TABLE request is generated from a sample row built by a
+ * deserializer from the declared types of the structure.
  * This should be refactored and simplified.
  */
 
+use chrono::NaiveDateTime;
 use serde::{de, ser, Serialize};
+use sqlite::Value;
 
 /******************************************************************************/
 /********************************** error *************************************/
@@ -84,6 +89,149 @@ impl std::error::Error for Error {}
 // INSERT INTO table_name (column1, column2, column3, ...) VALUES (value1, value2, value3, ...);
 // CREATE TABLE IF NOT EXISTS table_name (column1 datatype, column2 datatype, column3 datatype);
 
+// How the dates are stored, each format being understood by the SQLite date
+// functions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum DateFormat {
+  // "2009-03-05T22:28:34.823" as TEXT
+  #[default]
+  Iso,
+  // Seconds since 1970-01-01 as INTEGER
+  Epoch,
+  // Fractional days since the Julian epoch as REAL
+  Julian,
+}
+
+// Codes and names of the variants of a fieldless enum, stored in its own table
+// when the enums are stored as codes.
+#[derive(Debug)]
+pub struct LookupTable {
+  // Name of the enum as seen by serde
+  pub enum_name: &'static str,
+  pub table_name: &'static str,
+  pub variants: &'static [(i64, &'static str)],
+}
+
+// A column of a table (named after the structure) referencing the id of another
+#[derive(Debug)]
+pub struct ForeignKey {
+  pub table: &'static str,
+  pub column: &'static str,
+  pub references: &'static str,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+  pub dates: DateFormat,
+  // Store the enums listed in `lookup_tables` as their code instead of their name
+  pub enum_codes: bool,
+  pub lookup_tables: &'static [LookupTable],
+  // Foreign keys to declare, along with the ones to the lookup tables when
+  // storing codes, and whose columns are indexed
+  pub foreign_keys: &'static [ForeignKey],
+  // Store all the sites in the same tables (see `to_unified_table_name`),
+  // their rows told apart by a site_id column referencing SITE_TABLE
+  pub unified: bool,
+}
+
+impl Options {
+  fn get_enum_code(&self, name: &str, variant: &str) -> Result<i64> {
+    self.lookup_tables.iter()
+      .filter(|table| table.enum_name == name)
+      .flat_map(|table| table.variants.iter())
+      .find(|(_, variant_name)| *variant_name == variant)
+      .map(|(code, _)| *code)
+      .ok_or_else(|| Error::Message(format!("no code for {}::{}", name, variant)))
+  }
+
+  // The options shaping the tables, e.g. "--dates iso --unified". Tables
+  // created with other options have to be loaded again.
+  pub fn schema_key(&self) -> String {
+    let mut key = format!("--dates {:?}", self.dates).to_lowercase();
+    if self.enum_codes {
+      key += " --enum-codes";
+    }
+    if !self.foreign_keys.is_empty() {
+      key += " --foreign-keys";
+    }
+    if self.unified {
+      key += " --unified";
+    }
+    key
+  }
+
+  // Name of the table storing the structures named `name` of a site
+  fn get_table_name(&self, table_prefix: &str, name: &str) -> String {
+    if self.unified {
+      to_unified_table_name(name)
+    } else {
+      format!("{}_{}", table_prefix, name)
+    }
+  }
+}
+
+// Table of the sites when they share the same tables
+pub const SITE_TABLE: &str = "sites";
+
+// Name of the table shared by all the sites for the structures named `name`
+// "Post" -> "posts", "PostHistory" -> "post_histories"
+pub fn to_unified_table_name(name: &str) -> String {
+  let name = sanitize_key(name);
+  match name.strip_suffix('y') {
+    Some(stem) => format!("{}ies", stem),
+    None => format!("{}s", name),
+  }
+}
+
+// CREATE TABLE IF NOT EXISTS table_name (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+// INSERT OR REPLACE INTO table_name VALUES (code1, 'name1'), (code2, 'name2'), ...;
+pub fn to_lookup_table(table: &LookupTable) -> String {
+  let values = table.variants.iter()
+    .map(|(code, name)| format!("({}, '{}')", code, name))
+    .collect::<Vec<String>>()
+    .join(",");
+  format!("CREATE TABLE IF NOT EXISTS [{0}] (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+    INSERT OR REPLACE INTO [{0}] VALUES {1};", table.table_name, values)
+}
+
+// Name of the newtype struct the dates are serialized as (see
+// `serialize_date_time`) to tell them apart from the other strings. It wraps
+// the number of milliseconds since 1970-01-01.
+const DATE_TIME: &str = "sql_utils::DateTime";
+
+// To be used with `#[serde(serialize_with = "...")]` on the NaiveDateTime fields.
+pub fn serialize_date_time<S: ser::Serializer>(date: &NaiveDateTime, serializer: S)
+  -> std::result::Result<S::Ok, S::Error> {
+  serializer.serialize_newtype_struct(DATE_TIME, &date.timestamp_millis())
+}
+
+// Same as `serialize_date_time` for the optional dates.
+pub fn serialize_optional_date_time<S: ser::Serializer>(date: &Option<NaiveDateTime>, serializer: S)
+  -> std::result::Result<S::Ok, S::Error> {
+  struct DateTime<'a>(&'a NaiveDateTime);
+  impl Serialize for DateTime<'_> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+      serialize_date_time(self.0, serializer)
+    }
+  }
+  match date {
+    Some(date) => serializer.serialize_some(&DateTime(date)),
+    None => serializer.serialize_none(),
+  }
+}
+
+fn to_date_value(milliseconds: i64, format: DateFormat) -> Result<Value> {
+  Ok(match format {
+    DateFormat::Iso => {
+      let date = NaiveDateTime::from_timestamp_millis(milliseconds)
+        .ok_or_else(|| Error::Message(format!("invalid timestamp {}", milliseconds)))?;
+      Value::String(date.format("%Y-%m-%dT%H:%M:%S%.3f").to_string())
+    },
+    DateFormat::Epoch => Value::Integer(milliseconds.div_euclid(1000)),
+    DateFormat::Julian => Value::Float(milliseconds as f64 / 86_400_000.0 + 2_440_587.5),
+  })
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum SqlValue {
   INTEGER(i64),
@@ -96,26 +244,91 @@ pub struct Serializer {
   insert_stmt: String,
   create_stmt: String,
   table_prefix: String,
+  table_name: String,
+  options: Options,
   keys: Vec<(String, SqlValue)>,
   values: Vec<SqlValue>,
+  // Lookup table of the enum serialized as a code
+  lookup_table: Option<&'static str>,
+  // Columns referencing another table, the referenced table and whether it
+  // holds rows of the site (as opposed to a lookup table)
+  references: Vec<(String, String, bool)>,
+}
+
+impl Serializer {
+  fn new(table_prefix: &str, options: Options) -> Serializer {
+    Serializer {
+      sql_value: None,
+      insert_stmt: String::new(),
+      create_stmt: String::new(),
+      table_prefix: table_prefix.to_string(),
+      table_name: String::new(),
+      options,
+      keys: Vec::new(),
+      values: Vec::new(),
+      lookup_table: None,
+      references: Vec::new(),
+    }
+  }
+}
+
+// Serializes a sample row of the table
+fn serialize_sample<T>(table_prefix: &str, options: &Options) -> Result<Serializer>
+  where T: Serialize + de::DeserializeOwned {
+  let value = get_sample_row::<T>()?;
+  let mut serializer = Serializer::new(table_prefix, *options);
+  value.serialize(&mut serializer)?;
+  Ok(serializer)
 }
 
 // Creates a "create" statement. To be executable once to create the table and
 // creates an insert query used to prepare a statement.
 // INSERT INTO table VALUE (?, ?, ...)
-pub fn to_init_table<T>(value: &T, table_prefix: &str) -> Result<(String, String)> where T: Serialize {
-  let mut serializer = Serializer {
-    sql_value: None,
-    insert_stmt: String::new(),
-    create_stmt: String::new(),
-    table_prefix: table_prefix.to_string(),
-    keys: Vec::new(),
-    values: Vec::new(),
-  };
-  value.serialize(&mut serializer)?;
+// The statements only depend on the type of the rows, not on their content.
+// When the tables are unified, the first parameter of the insert statement is
+// the site_id, followed by the values returned by `bind_stmt`.
+pub fn to_init_table<T>(table_prefix: &str, options: &Options) -> Result<(String, String)>
+  where T: Serialize + de::DeserializeOwned {
+  let serializer = serialize_sample::<T>(table_prefix, options)?;
   Ok((serializer.create_stmt, serializer.insert_stmt))
 }
 
+// Same insert statement as `to_init_table` but inserting as many rows at once
+// as `max_parameters` parameters allow. Returns the statement and its number
+// of rows, whose values are bound one row after the other.
+pub fn to_batch_insert_stmt<T>(table_prefix: &str, options: &Options, max_parameters: usize) -> Result<(String, usize)>
+  where T: Serialize + de::DeserializeOwned {
+  let serializer = serialize_sample::<T>(table_prefix, options)?;
+  let columns = serializer.values.len() + usize::from(options.unified);
+  let rows = (max_parameters / columns).max(1);
+  let row = format!(",({})", vec!["?"; columns].join(","));
+  Ok((format!("{}{};", serializer.insert_stmt.trim_end_matches(';'), row.repeat(rows - 1)), rows))
+}
+
+// CREATE INDEX statements for the columns referencing other tables, to be
+// executed once the table is loaded.
+pub fn to_index_stmts<T>(table_prefix: &str, options: &Options) -> Result<Vec<String>>
+  where T: Serialize + de::DeserializeOwned {
+  let serializer = serialize_sample::<T>(table_prefix, options)?;
+  Ok(serializer.references.iter()
+    .map(|(column_name, _, per_site)| {
+      let columns = if options.unified && *per_site {
+        format!("site_id, {}", column_name)
+      } else {
+        column_name.clone()
+      };
+      format!("CREATE INDEX IF NOT EXISTS [{0}_{1}] ON [{0}] ({2});", serializer.table_name, column_name, columns)
+    })
+    .collect())
+}
+
+// Name of the table the statements returned by `to_init_table` operate on.
+pub fn to_table_name<T>(table_prefix: &str, options: &Options) -> Result<String>
+  where T: Serialize + de::DeserializeOwned {
+  let serializer = serialize_sample::<T>(table_prefix, options)?;
+  Ok(serializer.table_name)
+}
+
 impl<'a> ser::Serializer for &'a mut Serializer {
   type Ok = ();
   type Error = Error;
@@ -127,10 +340,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
   type SerializeStruct = Self;
   type SerializeStructVariant = ser::Impossible<Self::Ok, Self::Error>;
 
-  fn serialize_bool(self, v: bool) -> Result<()> {
-    self.sql_value = Some(SqlValue::TEXT(String::from(if v { "true" } else { "false" })));
-    Ok(())
-  }
+  fn serialize_bool(self, v: bool) -> Result<()> { self.serialize_i64(i64::from(v)) }
   fn serialize_i8(self, v: i8) -> Result<()> { self.serialize_i64(i64::from(v)) }
   fn serialize_i16(self, v: i16) -> Result<()> { self.serialize_i64(i64::from(v)) }
   fn serialize_i32(self, v: i32) -> Result<()> { self.serialize_i64(i64::from(v)) }
@@ -156,9 +366,10 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     Ok(())
   }
   fn serialize_bytes(self, _v: &[u8]) -> Result<()> { panic!("serialize_bytes not supported") }
+  // The schema is built from a sample row in which every field has a value
+  // (SampleDeserializer), a missing one has no column type.
   fn serialize_none(self) -> Result<()> {
-    self.sql_value = Some(SqlValue::TEXT(String::from("NULL")));
-    Ok(())
+    Err(Error::Message(String::from("no column type for a missing value")))
   }
   fn serialize_some<T>(self, value: &T) -> Result<()>
   where
@@ -170,21 +381,39 @@ impl<'a> ser::Serializer for &'a mut Serializer {
   fn serialize_unit_struct(self, _name: &'static str) -> Result<()> { panic!("serialize_unit_struct not supported") }
   fn serialize_unit_variant(
     self,
-    _name: &'static str,
+    name: &'static str,
     _variant_index: u32,
     variant: &'static str,
   ) -> Result<()> {
+    if self.options.enum_codes {
+      self.lookup_table = self.options.lookup_tables.iter()
+        .find(|table| table.enum_name == name)
+        .map(|table| table.table_name);
+      return self.serialize_i64(self.options.get_enum_code(name, variant)?);
+    }
     self.serialize_str(variant)
   }
   fn serialize_newtype_struct<T>(
     self,
-    _name: &'static str,
-    _value: &T,
+    name: &'static str,
+    value: &T,
   ) -> Result<()>
   where
     T: ?Sized + Serialize,
   {
-    panic!("serialize_newtype_struct not supported")
+    if name != DATE_TIME {
+      panic!("serialize_newtype_struct not supported")
+    }
+    value.serialize(&mut *self)?;
+    if let Some(SqlValue::INTEGER(milliseconds)) = self.sql_value {
+      self.sql_value = Some(match to_date_value(milliseconds, self.options.dates)? {
+        Value::Integer(v) => SqlValue::INTEGER(v),
+        Value::Float(v) => SqlValue::REAL(v),
+        Value::String(v) => SqlValue::TEXT(v),
+        _ => unreachable!(),
+      });
+    }
+    Ok(())
   }
   fn serialize_newtype_variant<T>(
     self,
@@ -228,16 +457,13 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     name: &'static str,
     len: usize,
   ) -> Result<Self::SerializeStruct> {
+    self.table_name = self.options.get_table_name(&self.table_prefix, name);
     self.insert_stmt += "INSERT INTO [";
-    self.insert_stmt += &self.table_prefix;
-    self.insert_stmt += "_";
-    self.insert_stmt += name;
+    self.insert_stmt += &self.table_name;
     self.insert_stmt += "] (";
 
     self.create_stmt += "CREATE TABLE IF NOT EXISTS [";
-    self.create_stmt += &self.table_prefix;
-    self.create_stmt += "_";
-    self.create_stmt += name;
+    self.create_stmt += &self.table_name;
     self.create_stmt += "] (";
     self.serialize_map(Some(len))
   }
@@ -308,14 +534,7 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
     T: ?Sized + Serialize,
   {
     {
-      let mut serializer = Serializer {
-        sql_value: None,
-        table_prefix: self.table_prefix.clone(),
-        insert_stmt: String::from(""),
-        create_stmt: String::from(""),
-        keys: Vec::new(),
-        values: Vec::new(),
-      };
+      let mut serializer = Serializer::new(&self.table_prefix, self.options);
       key.serialize(&mut serializer)?;
       let sql_value = serializer.sql_value.unwrap();
       let column_name = match sql_value.clone() {
@@ -326,26 +545,42 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
     }
 
     {
-      let mut serializer = Serializer {
-        sql_value: None,
-        table_prefix: self.table_prefix.clone(),
-        insert_stmt: String::from(""),
-        create_stmt: String::from(""),
-        keys: Vec::new(),
-        values: Vec::new(),
-      };
+      let mut serializer = Serializer::new(&self.table_prefix, self.options);
       value.serialize(&mut serializer)?;
       self.values.push(serializer.sql_value.unwrap());
+      if let Some(lookup_table) = serializer.lookup_table {
+        if !self.options.foreign_keys.is_empty() {
+          let (column_name, _) = self.keys.last().unwrap();
+          self.references.push((column_name.clone(), lookup_table.to_string(), false));
+        }
+      }
     }
 
     Ok(())
   }
 
   fn end(self) -> Result<()> {
+    for foreign_key in self.options.foreign_keys {
+      if self.options.get_table_name(&self.table_prefix, foreign_key.table) == self.table_name {
+        let references = self.options.get_table_name(&self.table_prefix, foreign_key.references);
+        self.references.push((foreign_key.column.to_string(), references, true));
+      }
+    }
+    if self.options.unified {
+      self.insert_stmt += "site_id,";
+      self.create_stmt += "site_id INTEGER NOT NULL,";
+    }
     self.insert_stmt += &self.keys.iter().map(|(column_name, _)| column_name.clone()).collect::<Vec<String>>().join(",");
     self.create_stmt += &self.keys.iter().zip(self.values.iter()).map(|((column_name, _), sql_type)| {
-      format!("{} {}", column_name, if column_name == "id" {
+      format!("{} {}", column_name, if column_name == "id" && self.options.unified {
+        "INTEGER NOT NULL"
+      } else if column_name == "id" {
         "INTEGER PRIMARY KEY UNIQUE"
+      } else if self.references.iter().any(|(reference, _, _)| reference == column_name) {
+        // The ids are strings in the dumps. Stored as such, they would not
+        // match the INTEGER ids they reference and joins could not use the
+        // index of the column.
+        "INTEGER"
       } else {
         match sql_type {
           SqlValue::TEXT(_) => "TEXT",
@@ -354,22 +589,114 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
         }
       })
     }).collect::<Vec<String>>().join(",");
+    // The ids are only unique within a site
+    if self.options.unified {
+      self.create_stmt += &format!(",PRIMARY KEY (site_id, id),FOREIGN KEY (site_id) REFERENCES [{}](id)", SITE_TABLE);
+    }
+    // Declared but not enforced (PRAGMA foreign_keys is off), the dumps
+    // reference deleted users and posts.
+    for (column_name, references, per_site) in &self.references {
+      self.create_stmt += &if self.options.unified && *per_site {
+        format!(",FOREIGN KEY (site_id, {}) REFERENCES [{}](site_id, id)", column_name, references)
+      } else {
+        format!(",FOREIGN KEY ({}) REFERENCES [{}](id)", column_name, references)
+      };
+    }
     self.insert_stmt += ") VALUES (";
     // self.insert_stmt += &self.values.join(",");
-    self.insert_stmt += &vec!["?"; self.values.len()].join(",");
+    let columns = self.values.len() + usize::from(self.options.unified);
+    self.insert_stmt += &vec!["?"; columns].join(",");
     self.insert_stmt += ");";
     self.create_stmt += ");";
     Ok(())
   }
 }
 
+/******************************************************************************/
+/******************************** sample row **********************************/
+/******************************************************************************/
+
+// Deserializes a row in which every field, optional or not, has a value, so
+// that the types of the columns can be found even for the fields that are
+// absent from the data. Strings are given a date so that the date fields
+// deserialize too.
+struct SampleDeserializer;
+
+const SAMPLE_STRING: &str = "1970-01-01T00:00:00.000";
+
+fn get_sample_row<T>() -> Result<T> where T: de::DeserializeOwned {
+  T::deserialize(SampleDeserializer)
+}
+
+impl<'de> de::Deserializer<'de> for SampleDeserializer {
+  type Error = Error;
+
+  fn deserialize_any<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+    Err(Error::Message("sample rows can only be built from self describing types".into()))
+  }
+  fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_bool(true) }
+  fn deserialize_i8<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_i64(1) }
+  fn deserialize_i16<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_i64(1) }
+  fn deserialize_i32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_i64(1) }
+  fn deserialize_i64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_i64(1) }
+  // Enums deserialized from their discriminant (serde_repr) all have a variant 1
+  fn deserialize_u8<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_u64(1) }
+  fn deserialize_u16<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_u64(1) }
+  fn deserialize_u32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_u64(1) }
+  fn deserialize_u64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_u64(1) }
+  fn deserialize_f32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_f64(1.0) }
+  fn deserialize_f64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_f64(1.0) }
+  fn deserialize_char<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_char('a') }
+  fn deserialize_str<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_str(SAMPLE_STRING) }
+  fn deserialize_string<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_str(SAMPLE_STRING) }
+  fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> { visitor.visit_some(self) }
+  fn deserialize_struct<V: de::Visitor<'de>>(
+    self,
+    _name: &'static str,
+    fields: &'static [&'static str],
+    visitor: V,
+  ) -> Result<V::Value> {
+    visitor.visit_map(SampleFields { fields: fields.iter() })
+  }
+
+  serde::forward_to_deserialize_any! {
+    bytes byte_buf unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+  }
+}
+
+struct SampleFields {
+  fields: std::slice::Iter<'static, &'static str>,
+}
+
+impl<'de> de::MapAccess<'de> for SampleFields {
+  type Error = Error;
+
+  fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+    match self.fields.next() {
+      Some(field) => seed.deserialize(de::IntoDeserializer::<Error>::into_deserializer(*field)).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+    seed.deserialize(SampleDeserializer)
+  }
+}
+
+/******************************************************************************/
+/******************************** sample row **********************************/
+/******************************************************************************/
+
 pub struct Binder {
-  output: Vec<String>,
+  options: Options,
+  output: Vec<Value>,
 }
 
-// Binds an INSERT statement to values
-pub fn bind_stmt<T>(value: &T) -> Result<Vec<String>> where T: Serialize {
+// Values to bind to the INSERT statement, in the order of its columns.
+// A None is bound as a SQL NULL.
+pub fn bind_stmt<T>(value: &T, options: &Options) -> Result<Vec<Value>> where T: Serialize {
   let mut binder = Binder {
+    options: *options,
     output: Vec::new(),
   };
   value.serialize(&mut binder)?;
@@ -387,24 +714,21 @@ impl<'a> ser::Serializer for &'a mut Binder {
   type SerializeStruct = Self;
   type SerializeStructVariant = ser::Impossible<Self::Ok, Self::Error>;
 
-  fn serialize_bool(self, v: bool) -> Result<()> {
-    self.output.push((if v { "true" } else { "false" }).into());
-    Ok(())
-  }
+  fn serialize_bool(self, v: bool) -> Result<()> { self.serialize_i64(i64::from(v)) }
   fn serialize_i8(self, v: i8) -> Result<()> { self.serialize_i64(i64::from(v)) }
   fn serialize_i16(self, v: i16) -> Result<()> { self.serialize_i64(i64::from(v)) }
   fn serialize_i32(self, v: i32) -> Result<()> { self.serialize_i64(i64::from(v)) }
-  fn serialize_i64(self, v: i64) -> Result<()> { self.output.push(v.to_string()); Ok(()) }
+  fn serialize_i64(self, v: i64) -> Result<()> { self.output.push(Value::Integer(v)); Ok(()) }
   fn serialize_u8(self, v: u8) -> Result<()> { self.serialize_u64(u64::from(v)) }
   fn serialize_u16(self, v: u16) -> Result<()> { self.serialize_u64(u64::from(v)) }
   fn serialize_u32(self, v: u32) -> Result<()> { self.serialize_u64(u64::from(v)) }
-  fn serialize_u64(self, v: u64) -> Result<()> { self.output.push(v.to_string()); Ok(()) }
+  fn serialize_u64(self, v: u64) -> Result<()> { self.output.push(Value::Integer(v as i64)); Ok(()) }
   fn serialize_f32(self, v: f32) -> Result<()> { self.serialize_f64(f64::from(v)) }
-  fn serialize_f64(self, v: f64) -> Result<()> { self.output.push(v.to_string()); Ok(()) }
+  fn serialize_f64(self, v: f64) -> Result<()> { self.output.push(Value::Float(v)); Ok(()) }
   fn serialize_char(self, v: char) -> Result<()> { self.serialize_str(&v.to_string()) }
-  fn serialize_str(self, v: &str) -> Result<()> { self.output.push(v.into()); Ok(()) }
+  fn serialize_str(self, v: &str) -> Result<()> { self.output.push(Value::String(v.into())); Ok(()) }
   fn serialize_bytes(self, _v: &[u8]) -> Result<()> { panic!("serialize_bytes not supported") }
-  fn serialize_none(self) -> Result<()> { self.output.push("NULL".into()); Ok(()) }
+  fn serialize_none(self) -> Result<()> { self.output.push(Value::Null); Ok(()) }
   fn serialize_some<T>(self, value: &T) -> Result<()>
   where
     T: ?Sized + Serialize,
@@ -415,21 +739,31 @@ impl<'a> ser::Serializer for &'a mut Binder {
   fn serialize_unit_struct(self, _name: &'static str) -> Result<()> { panic!("serialize_unit_struct not supported") }
   fn serialize_unit_variant(
     self,
-    _name: &'static str,
+    name: &'static str,
     _variant_index: u32,
     variant: &'static str,
   ) -> Result<()> {
+    if self.options.enum_codes {
+      return self.serialize_i64(self.options.get_enum_code(name, variant)?);
+    }
     self.serialize_str(variant)
   }
   fn serialize_newtype_struct<T>(
     self,
-    _name: &'static str,
-    _value: &T,
+    name: &'static str,
+    value: &T,
   ) -> Result<()>
   where
     T: ?Sized + Serialize,
   {
-    panic!("serialize_newtype_struct not supported")
+    if name != DATE_TIME {
+      panic!("serialize_newtype_struct not supported")
+    }
+    value.serialize(&mut *self)?;
+    if let Some(Value::Integer(milliseconds)) = self.output.pop() {
+      self.output.push(to_date_value(milliseconds, self.options.dates)?);
+    }
+    Ok(())
   }
   fn serialize_newtype_variant<T>(
     self,
@@ -521,6 +855,7 @@ impl<'a> ser::SerializeStruct for &'a mut Binder {
     T: ?Sized + Serialize,
   {
     let mut binder = Binder {
+      options: self.options,
       output: Vec::new(),
     };
     value.serialize(&mut binder)?;
@@ -532,3 +867,88 @@ impl<'a> ser::SerializeStruct for &'a mut Binder {
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{bind_stmt, to_date_value, to_init_table, DateFormat, Options};
+  use crate::se_struct;
+  use sqlite::Value;
+
+  // Type of the column in a CREATE TABLE statement
+  fn column_type<'a>(create_stmt: &'a str, column: &str) -> &'a str {
+    let columns = create_stmt.split_once('(').unwrap().1.trim_end_matches(");");
+    columns.split(',')
+      .find_map(|definition| definition.strip_prefix(column)?.strip_prefix(' '))
+      .unwrap_or_else(|| panic!("no column {} in {}", column, create_stmt))
+  }
+
+  fn post_column_types(options: &Options, columns: &[&str]) -> Vec<String> {
+    let (create_stmt, _) = to_init_table::<se_struct::Post>("site", options).unwrap();
+    columns.iter().map(|column| column_type(&create_stmt, column).to_string()).collect()
+  }
+
+  // The types come from the structure alone, whatever the rows hold
+  #[test]
+  fn column_types_do_not_depend_on_the_data() {
+    let options = Options::default();
+    assert_eq!(post_column_types(&options, &["id", "score", "view_count", "favorite_count", "body", "title"]),
+      ["INTEGER PRIMARY KEY UNIQUE", "INTEGER", "INTEGER", "INTEGER", "TEXT", "TEXT"]);
+  }
+
+  #[test]
+  fn date_columns_follow_the_date_format() {
+    for (dates, column_type) in [(DateFormat::Iso, "TEXT"), (DateFormat::Epoch, "INTEGER"), (DateFormat::Julian, "REAL")] {
+      let options = Options { dates, ..Options::default() };
+      assert_eq!(post_column_types(&options, &["creation_date", "deletion_date", "closed_date"]), [column_type; 3],
+        "{:?}", dates);
+    }
+  }
+
+  #[test]
+  fn enum_columns_hold_names_or_codes() {
+    let options = Options { lookup_tables: &se_struct::LOOKUP_TABLES, ..Options::default() };
+    assert_eq!(post_column_types(&options, &["post_type_id"]), ["TEXT"]);
+    let options = Options { enum_codes: true, ..options };
+    assert_eq!(post_column_types(&options, &["post_type_id"]), ["INTEGER"]);
+  }
+
+  // 2009-03-05T22:28:34.823
+  const MILLISECONDS: i64 = 1_236_292_114_823;
+
+  #[derive(serde::Serialize)]
+  struct Row {
+    id: i64,
+    count: Option<i64>,
+    name: Option<String>,
+  }
+
+  #[test]
+  fn missing_values_bind_as_null() {
+    let row = Row { id: 1, count: None, name: None };
+    assert_eq!(bind_stmt(&row, &Options::default()).unwrap(), [Value::Integer(1), Value::Null, Value::Null]);
+    let row = Row { id: 2, count: Some(-3), name: Some("NULL".to_string()) };
+    assert_eq!(bind_stmt(&row, &Options::default()).unwrap(),
+      [Value::Integer(2), Value::Integer(-3), Value::String("NULL".to_string())]);
+  }
+
+  #[test]
+  fn iso_dates_keep_the_milliseconds() {
+    assert_eq!(to_date_value(MILLISECONDS, DateFormat::Iso).unwrap(),
+      Value::String("2009-03-05T22:28:34.823".to_string()));
+    assert_eq!(to_date_value(0, DateFormat::Iso).unwrap(), Value::String("1970-01-01T00:00:00.000".to_string()));
+    assert!(to_date_value(i64::MAX, DateFormat::Iso).is_err());
+  }
+
+  #[test]
+  fn epoch_dates_are_rounded_down_to_the_second() {
+    assert_eq!(to_date_value(MILLISECONDS, DateFormat::Epoch).unwrap(), Value::Integer(1_236_292_114));
+    assert_eq!(to_date_value(-1, DateFormat::Epoch).unwrap(), Value::Integer(-1));
+  }
+
+  #[test]
+  fn julian_dates_count_from_the_julian_epoch() {
+    assert_eq!(to_date_value(0, DateFormat::Julian).unwrap(), Value::Float(2_440_587.5));
+    // 2000-01-01T12:00:00
+    assert_eq!(to_date_value(946_728_000_000, DateFormat::Julian).unwrap(), Value::Float(2_451_545.0));
+  }
+}
@@ -0,0 +1,65 @@
+/*
+ * Limits shared by all the concurrent downloads:
+ * - a token bucket capping the overall bandwidth and
+ * - a semaphore per host capping the number of simultaneous connections.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+struct Bucket {
+  tokens: f64,
+  last_refill: Instant,
+}
+
+pub struct Throttle {
+  // Bytes per second
+  max_rate: Option<u64>,
+  bucket: Mutex<Bucket>,
+  max_host_connections: Option<usize>,
+  hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl Throttle {
+  pub fn new(max_rate: Option<u64>, max_host_connections: Option<usize>) -> Throttle {
+    Throttle {
+      max_rate,
+      bucket: Mutex::new(Bucket { tokens: max_rate.unwrap_or(0) as f64, last_refill: Instant::now() }),
+      max_host_connections,
+      hosts: Mutex::new(HashMap::new()),
+    }
+  }
+
+  // Waits for a connection slot to the host of the url. The slot is released
+  // when the returned permit is dropped.
+  pub async fn acquire_host(&self, url: &reqwest::Url) -> Option<OwnedSemaphorePermit> {
+    let max_host_connections = self.max_host_connections?;
+    let semaphore = self.hosts.lock().unwrap()
+      .entry(url.host_str().unwrap_or("").to_string())
+      .or_insert_with(|| Arc::new(Semaphore::new(max_host_connections)))
+      .clone();
+    Some(semaphore.acquire_owned().await.expect("host semaphores are never closed"))
+  }
+
+  // Takes `bytes` tokens from the bucket, waiting for the bucket to refill if
+  // it is in debt. The bucket holds at most one second worth of tokens.
+  pub async fn consume(&self, bytes: usize) {
+    let max_rate = match self.max_rate {
+      Some(max_rate) => max_rate as f64,
+      None => return,
+    };
+    let wait = {
+      let mut bucket = self.bucket.lock().unwrap();
+      let now = Instant::now();
+      let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+      bucket.tokens = f64::min(max_rate, bucket.tokens + elapsed * max_rate) - bytes as f64;
+      bucket.last_refill = now;
+      if bucket.tokens < 0.0 { -bucket.tokens / max_rate } else { 0.0 }
+    };
+    if wait > 0.0 {
+      tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+    }
+  }
+}
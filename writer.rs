@@ -0,0 +1,347 @@
+/*
+ * Single writer owning the connection to the database. The jobs parse their
+ * files in parallel and send the rows, by batches, to the writer thread, the
+ * only one to access the database while the sites are processed.
+ * The rows are written in a transaction committed whenever a table has been
+ * loaded (or the ledger written to), so the tables loaded concurrently may be
+ * committed partially. A table is only recorded as loaded in the ledger once
+ * all its rows are written though, an interrupted one is replaced on the next
+ * run.
+ * A bulk load (--bulk) trades the durability of the database for speed: the
+ * rows are inserted by batches, SQLite does not wait for the disk and the
+ * transaction is committed every COMMIT_ROWS rows. The indexes are only
+ * created, and the tables recorded in the ledger, once every table is loaded.
+ */
+
+use crate::sql_utils::Options;
+use crate::{fts, ledger, sites};
+use sqlite::{Connection, Statement, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// Number of rows sent to the writer at once
+const BATCH_SIZE: usize = 1000;
+// Number of messages waiting for the writer past which the jobs wait too
+const QUEUE_SIZE: usize = 64;
+// Number of parameters of the statements inserting rows by batches, the lowest
+// limit (SQLITE_MAX_VARIABLE_NUMBER) of the SQLite versions
+pub const MAX_PARAMETERS: usize = 999;
+// Number of rows a bulk load inserts between two commits
+const COMMIT_ROWS: usize = 100_000;
+// In KiB, 256 MiB
+const BULK_CACHE_SIZE: i64 = 262_144;
+
+enum Message {
+  // Starts loading a table: drops it, or removes the rows of the site from it
+  // when it is shared, and creates it
+  Begin {
+    load: u64,
+    site: String,
+    table_prefix: String,
+    table_name: String,
+    create_stmt: String,
+    insert_stmt: String,
+    // Statement inserting several rows at once and its number of rows
+    batch_insert_stmt: Option<(String, usize)>,
+  },
+  Rows { load: u64, rows: Vec<Vec<Value>> },
+  // Indexes the table and records it in the ledger, whether rows were loaded or not
+  End { load: u64, index_stmts: Vec<String>, entry: ledger::Entry, reply: Sender<sqlite::Result<()>> },
+  GetLedgerEntry { site: String, stage: String, reply: Sender<sqlite::Result<Option<String>>> },
+  RecordLedgerEntry { entry: ledger::Entry, reply: Sender<sqlite::Result<()>> },
+  // Builds the full-text indexes of a site and records them in the ledger
+  Index { table_prefix: String, entry: ledger::Entry, reply: Sender<sqlite::Result<()>> },
+}
+
+fn stopped_error() -> sqlite::Error {
+  sqlite::Error { code: None, message: Some("the database writer stopped".to_string()) }
+}
+
+#[derive(Clone)]
+pub struct Writer {
+  sender: SyncSender<Message>,
+  next_load: Arc<AtomicU64>,
+}
+
+impl Writer {
+  // Starts the writer thread. It stops, committing what was written, once
+  // every copy of the returned writer is dropped.
+  pub fn spawn(connection: Connection, options: Options, bulk: bool) -> (Writer, JoinHandle<sqlite::Result<()>>) {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    let thread = std::thread::spawn(move || run(&connection, &options, bulk, receiver));
+    (Writer { sender, next_load: Arc::new(AtomicU64::new(0)) }, thread)
+  }
+
+  fn send(&self, message: Message) -> sqlite::Result<()> {
+    self.sender.send(message).map_err(|_| stopped_error())
+  }
+
+  fn request<T>(&self, message: impl FnOnce(Sender<sqlite::Result<T>>) -> Message) -> sqlite::Result<T> {
+    let (reply, response) = mpsc::channel();
+    self.send(message(reply))?;
+    response.recv().map_err(|_| stopped_error())?
+  }
+
+  // Starts loading a table of the site whose tables are prefixed with
+  // `table_prefix` (unless they are shared)
+  pub fn load(&self, site: &str, table_prefix: &str) -> Load {
+    Load {
+      writer: self.clone(),
+      id: self.next_load.fetch_add(1, Ordering::Relaxed),
+      site: site.to_string(),
+      table_prefix: table_prefix.to_string(),
+      rows: Vec::new(),
+    }
+  }
+
+  pub fn get_ledger_entry(&self, site: &str, stage: &str) -> sqlite::Result<Option<String>> {
+    self.request(|reply| Message::GetLedgerEntry { site: site.to_string(), stage: stage.to_string(), reply })
+  }
+
+  pub fn record_ledger_entry(&self, entry: ledger::Entry) -> sqlite::Result<()> {
+    self.request(|reply| Message::RecordLedgerEntry { entry, reply })
+  }
+
+  // Builds the full-text indexes of the site whose tables are prefixed with
+  // `table_prefix` (unless they are shared) and records `entry`
+  pub fn index(&self, table_prefix: &str, entry: ledger::Entry) -> sqlite::Result<()> {
+    self.request(|reply| Message::Index { table_prefix: table_prefix.to_string(), entry, reply })
+  }
+}
+
+// A table being loaded. Its rows are buffered and sent to the writer by batches.
+pub struct Load {
+  writer: Writer,
+  id: u64,
+  site: String,
+  table_prefix: String,
+  rows: Vec<Vec<Value>>,
+}
+
+impl Load {
+  // To be called before the first row is inserted. The batch insert statement
+  // is only used by the bulk loads.
+  pub fn begin(&self, table_name: String, create_stmt: String, insert_stmt: String,
+    batch_insert_stmt: Option<(String, usize)>) -> sqlite::Result<()> {
+    self.writer.send(Message::Begin {
+      load: self.id,
+      site: self.site.clone(),
+      table_prefix: self.table_prefix.clone(),
+      table_name,
+      create_stmt,
+      insert_stmt,
+      batch_insert_stmt,
+    })
+  }
+
+  // Values to bind to the insert statement given to `begin`
+  pub fn insert(&mut self, row: Vec<Value>) -> sqlite::Result<()> {
+    self.rows.push(row);
+    if self.rows.len() == BATCH_SIZE {
+      let rows = std::mem::replace(&mut self.rows, Vec::with_capacity(BATCH_SIZE));
+      self.writer.send(Message::Rows { load: self.id, rows })?;
+    }
+    Ok(())
+  }
+
+  // Waits for the rows to be written, then creates the indexes and records `entry`.
+  pub fn finish(mut self, index_stmts: Vec<String>, entry: ledger::Entry) -> sqlite::Result<()> {
+    if !self.rows.is_empty() {
+      let rows = std::mem::take(&mut self.rows);
+      self.writer.send(Message::Rows { load: self.id, rows })?;
+    }
+    self.writer.request(|reply| Message::End { load: self.id, index_stmts, entry, reply })
+  }
+}
+
+// A table being written to
+struct Table<'a> {
+  insert_statement: Statement<'a>,
+  batch_insert_statement: Option<(Statement<'a>, usize)>,
+  // Prepended to the rows of the shared tables
+  site_id: Option<i64>,
+  count: usize,
+}
+
+// Where the tables of the site are, registering it in the shared tables if needed
+fn get_site(connection: &Connection, options: &Options, site: &str, table_prefix: &str) -> sqlite::Result<sites::Site> {
+  let tables = if options.unified {
+    sites::Tables::Unified(sites::get_id(connection, site)?)
+  } else {
+    sites::Tables::Prefixed(table_prefix.to_string())
+  };
+  Ok(sites::Site { name: site.to_string(), tables })
+}
+
+fn begin<'a>(connection: &'a Connection, site: &sites::Site, table_name: &str, create_stmt: &str,
+  insert_stmt: &str, batch_insert_stmt: Option<&(String, usize)>) -> sqlite::Result<Table<'a>> {
+  let site_id = match site.tables {
+    // The shared tables only lose the previous rows of the site
+    sites::Tables::Unified(site_id) => {
+      connection.execute(create_stmt)?;
+      let mut delete_statement = connection.prepare(format!("DELETE FROM [{}] WHERE site_id = ?", table_name))?;
+      delete_statement.bind((1, site_id))?;
+      delete_statement.next()?;
+      Some(site_id)
+    },
+    sites::Tables::Prefixed(_) => {
+      connection.execute(format!("DROP TABLE IF EXISTS [{}];", table_name))?;
+      connection.execute(create_stmt)?;
+      None
+    },
+  };
+  // The full-text indexes would keep matching the previous rows
+  if table_name == site.table("Post") || table_name == site.table("Comment") {
+    fts::remove(connection, site)?;
+  }
+  let batch_insert_statement = match batch_insert_stmt {
+    Some((batch_insert_stmt, rows)) => Some((connection.prepare(batch_insert_stmt)?, *rows)),
+    None => None,
+  };
+  Ok(Table { insert_statement: connection.prepare(insert_stmt)?, batch_insert_statement, site_id, count: 0 })
+}
+
+fn insert(table: &mut Table, mut rows: Vec<Vec<Value>>) -> sqlite::Result<()> {
+  if let Some(site_id) = table.site_id {
+    for row in rows.iter_mut() {
+      row.insert(0, Value::Integer(site_id));
+    }
+  }
+  let mut remaining_rows = &rows[..];
+  if let Some((batch_insert_statement, batch_rows)) = &mut table.batch_insert_statement {
+    let mut batches = rows.chunks_exact(*batch_rows);
+    for batch in &mut batches {
+      batch_insert_statement.reset()?;
+      for (index, value) in batch.iter().flatten().enumerate() {
+        batch_insert_statement.bind((index + 1, value))?;
+      }
+      batch_insert_statement.next()?;
+    }
+    remaining_rows = batches.remainder();
+  }
+  for row in remaining_rows {
+    table.insert_statement.reset()?;
+    table.insert_statement.bind(&row[..])?;
+    table.insert_statement.next()?;
+  }
+  table.count += rows.len();
+  Ok(())
+}
+
+fn end(connection: &Connection, table: Option<Table>, index_stmts: &[String], entry: &ledger::Entry)
+  -> sqlite::Result<()> {
+  // Indexing once the rows are inserted is faster than maintaining the index
+  if table.is_some_and(|table| table.count > 0) {
+    for index_stmt in index_stmts {
+      connection.execute(index_stmt)?;
+    }
+  }
+  ledger::record(connection, entry)?;
+  // The full-text indexes are stale once a table is reloaded
+  ledger::remove(connection, &entry.site, ledger::FTS)?;
+  commit(connection)
+}
+
+fn index(connection: &Connection, options: &Options, table_prefix: &str, entry: &ledger::Entry) -> sqlite::Result<()> {
+  let site = get_site(connection, options, &entry.site, table_prefix)?;
+  if sites::table_exists(connection, &site.table("Post"))? {
+    fts::index_posts(connection, &site)?;
+  }
+  if sites::table_exists(connection, &site.table("Comment"))? {
+    fts::index_comments(connection, &site)?;
+  }
+  ledger::record(connection, entry)?;
+  commit(connection)
+}
+
+fn commit(connection: &Connection) -> sqlite::Result<()> {
+  connection.execute("COMMIT; BEGIN TRANSACTION;")
+}
+
+// What a bulk load leaves for the end
+#[derive(Default)]
+struct Deferred {
+  index_stmts: Vec<String>,
+  entries: Vec<ledger::Entry>,
+}
+
+fn run(connection: &Connection, options: &Options, bulk: bool, receiver: Receiver<Message>) -> sqlite::Result<()> {
+  // The tables being loaded, or the error that interrupted their load
+  let mut tables: HashMap<u64, sqlite::Result<Table>> = HashMap::new();
+  let mut deferred = Deferred::default();
+  let mut uncommitted_rows = 0;
+  if bulk {
+    connection.execute(format!("PRAGMA synchronous = OFF; PRAGMA cache_size = -{}; PRAGMA temp_store = MEMORY;",
+      BULK_CACHE_SIZE))?;
+  }
+  connection.execute("BEGIN TRANSACTION;")?;
+  // The requesters are gone when their reply cannot be sent, there is no one
+  // left to tell about the outcome.
+  for message in receiver {
+    match message {
+      Message::Begin { load, site, table_prefix, table_name, create_stmt, insert_stmt, batch_insert_stmt } => {
+        let batch_insert_stmt = batch_insert_stmt.as_ref().filter(|_| bulk);
+        let table = get_site(connection, options, &site, &table_prefix).and_then(|site|
+          begin(connection, &site, &table_name, &create_stmt, &insert_stmt, batch_insert_stmt));
+        tables.insert(load, table);
+      },
+      Message::Rows { load, rows } => {
+        if let Some(Ok(table)) = tables.get_mut(&load) {
+          uncommitted_rows += rows.len();
+          if let Err(e) = insert(table, rows) {
+            tables.insert(load, Err(e));
+          }
+        }
+        if bulk && uncommitted_rows >= COMMIT_ROWS {
+          commit(connection)?;
+          uncommitted_rows = 0;
+        }
+      },
+      Message::End { load, index_stmts, entry, reply } if bulk => {
+        let result = match tables.remove(&load) {
+          Some(Err(e)) => Err(e),
+          table => {
+            if table.is_some_and(|table| table.is_ok_and(|table| table.count > 0)) {
+              for index_stmt in index_stmts {
+                if !deferred.index_stmts.contains(&index_stmt) {
+                  deferred.index_stmts.push(index_stmt);
+                }
+              }
+            }
+            deferred.entries.push(entry.clone());
+            ledger::remove(connection, &entry.site, ledger::FTS)
+          },
+        };
+        let _ = reply.send(result);
+      },
+      Message::End { load, index_stmts, entry, reply } => {
+        let result = match tables.remove(&load) {
+          Some(Err(e)) => Err(e),
+          Some(Ok(table)) => end(connection, Some(table), &index_stmts, &entry),
+          None => end(connection, None, &index_stmts, &entry),
+        };
+        let _ = reply.send(result);
+      },
+      Message::GetLedgerEntry { site, stage, reply } => {
+        let _ = reply.send(ledger::get(connection, &site, &stage));
+      },
+      Message::RecordLedgerEntry { entry, reply } => {
+        let _ = reply.send(ledger::record(connection, &entry).and_then(|_| commit(connection)));
+      },
+      Message::Index { table_prefix, entry, reply } => {
+        let _ = reply.send(index(connection, options, &table_prefix, &entry));
+      },
+    }
+  }
+  drop(tables);
+  for index_stmt in &deferred.index_stmts {
+    connection.execute(index_stmt)?;
+  }
+  for entry in &deferred.entries {
+    ledger::record(connection, entry)?;
+  }
+  connection.execute("COMMIT;")
+}